@@ -0,0 +1,80 @@
+//! Emulation speed of `uboy run` compared to real hardware, run with
+//! `cargo bench --bench speed` and `--features trace-log` to include the
+//! cost of the trace log.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::{self, Command, Stdio},
+    time::Instant,
+};
+
+const FRAMES: u32 = 600;
+/* Frames per second of the hardware, 4194304 Hz / 70224 cycles per frame */
+const REAL_FPS: f64 = 4_194_304.0 / 70_224.0;
+
+/// A rom looping over memory reads and writes
+fn create_rom(dir: &Path) -> PathBuf {
+    let mut rom = vec![0; 0x8000];
+    rom[0x134..0x139].copy_from_slice(b"SPEED");
+    let code = [
+        0x01, 0x00, 0xC0, // LD BC, $C000
+        0x02, // LD (BC), A
+        0xE0, 0x80, // LDH ($FF80), A
+        0xF0, 0x44, // LDH A, ($FF44)
+        0xC3, 0x00, 0x01, // JP $0100
+    ];
+    rom[0x100..0x100 + code.len()].copy_from_slice(&code);
+    let path = dir.join("speed.gb");
+    fs::write(&path, rom).expect("Can not write the benchmark rom");
+    path
+}
+
+/// Runs the rom for the given frames, returns how many times faster than
+/// real time it ran
+fn bench(name: &str, rom: &Path, frames: u32, args: &[&str]) -> f64 {
+    let start = Instant::now();
+    let status = Command::new(env!("CARGO_BIN_EXE_uboy"))
+        .arg("run")
+        .args(args)
+        .args(["--frames", &frames.to_string()])
+        .arg(rom)
+        .stdout(Stdio::null())
+        .status()
+        .expect("Can not start uboy");
+    let seconds = start.elapsed().as_secs_f64();
+    assert!(status.success(), "{} failed", name);
+    let fps = frames as f64 / seconds;
+    println!(
+        "{:<24} {} frames in {:.3}s, {:.0} fps, {:.1}x real time",
+        name,
+        frames,
+        seconds,
+        fps,
+        fps / REAL_FPS
+    );
+    fps / REAL_FPS
+}
+
+fn main() {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR"));
+    let rom = create_rom(dir);
+
+    let mut slow = Vec::new();
+    for (name, args) in [
+        ("logging off", &["-l", "off"][..]),
+        ("default log level", &[][..]),
+    ] {
+        if bench(name, &rom, FRAMES, args) < 1.0 {
+            slow.push(name);
+        }
+    }
+    if cfg!(feature = "trace-log") {
+        // Only measured, the trace log is not expected to run in real time
+        bench("trace log", &rom, 10, &["--trace-log", "-l", "trace"]);
+    }
+    if !slow.is_empty() {
+        eprintln!("Slower than real time: {}", slow.join(", "));
+        process::exit(1);
+    }
+}
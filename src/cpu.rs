@@ -1,7 +1,13 @@
+use crate::disasm;
 use crate::mem::{CartHeader, Memory};
+use crate::state::{Savestate, StateReader, StateWriter};
+use crate::tracelog::{self, trace_event};
 #[allow(unused_imports)]
 use log::{warn, info, error, debug, trace};
-use std::fmt::{self, Debug, Formatter};
+use std::{
+    fmt::{self, Debug, Display, Formatter},
+    io::{self, Write},
+};
 
 #[derive(Default, PartialEq)]
 struct RegPair(u8, u8);
@@ -22,6 +28,34 @@ impl From<u16> for RegPair {
     }
 }
 
+/// 16 bit registers, 8 bit registers are the halves of a pair
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Reg {
+    AF,
+    BC,
+    DE,
+    HL,
+    SP,
+    PC,
+}
+
+/// Illegal opcode that hung the cpu, it never executes anything again
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Lockup {
+    pub op: u8,
+    pub addr: u16,
+}
+
+impl Display for Lockup {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "CPU locked up by illegal opcode {:02X} at {:04X}",
+            self.op, self.addr
+        )
+    }
+}
+
 pub struct Cpu {
     af: RegPair,
     bc: RegPair,
@@ -31,6 +65,8 @@ pub struct Cpu {
     pc: u16,
     cycle: u64,
     mem: Memory,
+    lockup: Option<Lockup>,
+    trace: Option<Box<dyn Write>>, /* gameboy-doctor log of every instruction */
 }
 
 impl Debug for Cpu {
@@ -47,11 +83,48 @@ impl Debug for Cpu {
     }
 }
 
-const OP_CYCLES: [u8; 0x100] = [
+impl Savestate for Cpu {
+    fn save_state(&self, out: &mut StateWriter) {
+        out.u16(self.af.get());
+        out.u16(self.bc.get());
+        out.u16(self.de.get());
+        out.u16(self.hl.get());
+        out.u16(self.sp);
+        out.u16(self.pc);
+        out.u64(self.cycle);
+        out.bool(self.lockup.is_some());
+        let lockup = self.lockup.unwrap_or(Lockup { op: 0, addr: 0 });
+        out.u8(lockup.op);
+        out.u16(lockup.addr);
+        self.mem.save_state(out);
+    }
+
+    fn load_state(&mut self, input: &mut StateReader) -> io::Result<()> {
+        self.af.set(input.u16()?);
+        self.bc.set(input.u16()?);
+        self.de.set(input.u16()?);
+        self.hl.set(input.u16()?);
+        self.sp = input.u16()?;
+        self.pc = input.u16()?;
+        self.cycle = input.u64()?;
+        let locked = input.bool()?;
+        let lockup = Lockup {
+            op: input.u8()?,
+            addr: input.u16()?,
+        };
+        self.lockup = if locked { Some(lockup) } else { None };
+        self.mem.load_state(input)
+    }
+}
+
+/* Cycles of every opcode, conditional branches when they are not taken. The
+ * cpu spends them one M-cycle at a time, the table is used by the disassembler
+ * and the op_cycles_match_execution test keeps the two in agreement */
+pub const OP_CYCLES: [u8; 0x100] = [
   /* 0   1    2   3   4   5   6   7   8   9   a  b   c   d  e   f */
       4, 12,  8,  8,  4,  4,  8,  4, 20,  8,  8, 8,  4,  4, 8,  4, //0
       4, 12,  8,  8,  4,  4,  8,  4, 12,  8,  8, 8,  4,  4, 8,  4, //1
-      8, 12,  8,  8,  4,  4,  8,  4, 12,  8,  8, 8,  4,  4, 8,  4, //2
+      8, 12,  8,  8,  4,  4,  8,  4,  8,  8,  8, 8,  4,  4, 8,  4, //2
       8, 12,  8,  8, 12, 12, 12,  4,  8,  8,  8, 8,  4,  4, 8,  4, //3
       4,  4,  4,  4,  4,  4,  8,  4,  4,  4,  4, 4,  4,  4, 8,  4, //4
       4,  4,  4,  4,  4,  4,  8,  4,  4,  4,  4, 4,  4,  4, 8,  4, //5
@@ -67,6 +140,18 @@ const OP_CYCLES: [u8; 0x100] = [
      12, 12,  8,  4,  0, 16,  8, 16, 12,  8, 16, 4,  0,  0, 8, 16, //f
 ];
 
+/// Cycles of a conditional branch when it is taken, `OP_CYCLES` has the
+/// not taken ones
+pub fn branch_cycles(op: u8) -> Option<u8> {
+    match op {
+        0x20 | 0x28 | 0x30 | 0x38 => Some(12), /* JR cc */
+        0xC0 | 0xC8 | 0xD0 | 0xD8 => Some(20), /* RET cc */
+        0xC2 | 0xCA | 0xD2 | 0xDA => Some(16), /* JP cc */
+        0xC4 | 0xCC | 0xD4 | 0xDC => Some(24), /* CALL cc */
+        _ => None,
+    }
+}
+
 #[allow(dead_code)]
 impl Cpu {
     pub fn new(mem: Memory) -> Self {
@@ -78,10 +163,85 @@ impl Cpu {
             sp: 0,
             pc: 0,
             cycle: 0,
-            mem: mem,
+            mem,
+            lockup: None,
+            trace: None,
         }
     }
 
+    pub fn mem(&self) -> &Memory {
+        &self.mem
+    }
+
+    pub fn mem_mut(&mut self) -> &mut Memory {
+        &mut self.mem
+    }
+
+    pub fn reg(&self, reg: Reg) -> u16 {
+        match reg {
+            Reg::AF => self.af.get(),
+            Reg::BC => self.bc.get(),
+            Reg::DE => self.de.get(),
+            Reg::HL => self.hl.get(),
+            Reg::SP => self.sp,
+            Reg::PC => self.pc,
+        }
+    }
+
+    /// Sets a register, the low nibble of F always reads as 0
+    pub fn set_reg(&mut self, reg: Reg, val: u16) {
+        match reg {
+            Reg::AF => self.af.set(val & 0xFFF0),
+            Reg::BC => self.bc.set(val),
+            Reg::DE => self.de.set(val),
+            Reg::HL => self.hl.set(val),
+            Reg::SP => self.sp = val,
+            Reg::PC => self.pc = val,
+        }
+    }
+
+    pub fn cycle(&self) -> u64 {
+        self.cycle
+    }
+
+    /// The illegal opcode the cpu hung on, if it did
+    pub fn lockup(&self) -> Option<Lockup> {
+        self.lockup
+    }
+
+    /// Writes a trace line before every instruction from now on
+    pub fn set_trace(&mut self, out: Box<dyn Write>) {
+        self.trace = Some(out);
+    }
+
+    pub fn flush_trace(&mut self) -> io::Result<()> {
+        match self.trace.as_mut() {
+            Some(out) => out.flush(),
+            None => Ok(()),
+        }
+    }
+
+    /// State before the next instruction in the format of gameboy-doctor
+    pub fn trace_line(&self) -> String {
+        let pcmem: Vec<String> = (0..4)
+            .map(|i| format!("{:02X}", self.mem.peek8(self.pc.wrapping_add(i))))
+            .collect();
+        format!(
+            "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X} PCMEM:{}",
+            self.af.0,
+            self.af.1,
+            self.bc.0,
+            self.bc.1,
+            self.de.0,
+            self.de.1,
+            self.hl.0,
+            self.hl.1,
+            self.sp,
+            self.pc,
+            pcmem.join(",")
+        )
+    }
+
     fn set_flag_zero(&mut self, val: bool){
         if val {
             self.af.1 |= 1<<7;
@@ -127,8 +287,18 @@ impl Cpu {
         self.af.1 & 1 << 4 != 0
     }
 
-    fn initialize(&mut self, cart: &CartHeader) {
-        if cart.gbc || cart.gbc_only {
+    /// NZ, Z, NC or C from bits 3 and 4 of a conditional opcode
+    fn condition(&self, op: u8) -> bool {
+        match (op >> 3) & 0x3 {
+            0 => !self.get_flag_zero(),
+            1 => self.get_flag_zero(),
+            2 => !self.get_flag_carry(),
+            _ => self.get_flag_carry(),
+        }
+    }
+
+    pub fn initialize(&mut self, _cart: &CartHeader) {
+        if self.mem.cgb_hardware() {
             self.af.set(0x1180);
             self.bc.set(0x0000);
             self.de.set(0xff56);
@@ -143,18 +313,72 @@ impl Cpu {
         self.pc = 0x0100;
     }
 
-    pub fn run(&mut self, cart: CartHeader) {
-        self.initialize(&cart);
+    pub fn run(&mut self) {
         loop {
+            self.step();
+        }
+    }
+
+    /// Runs until the ppu completed the given number of frames
+    pub fn run_frames(&mut self, frames: u64) {
+        let target = self.mem.frame() + frames;
+        while self.mem.frame() < target {
+            self.step();
+        }
+    }
+
+    /// Runs at least the given number of cycles, stopping at the first
+    /// instruction boundary after them
+    pub fn run_cycles(&mut self, cycles: u64) {
+        let target = self.cycle + cycles;
+        while self.cycle < target {
+            self.step();
+        }
+    }
+
+    /// Executes a single instruction and advances the rest of the system
+    pub fn step(&mut self) {
+        if self.lockup.is_some() {
+            // Nothing runs on a locked up cpu, not even interrupts, while
+            // the rest of the system keeps going
+            self.idle();
+        } else {
+            self.trace_instruction();
             let op = self.fetch();
-            info!("Op code is 0x{:x}.", op);
-            info!("{:04x?}", self);
             self.execute(op);
         }
+        // DMA transfers and speed switches halt the cpu after the instruction
+        let stall = self.mem.take_stall();
+        if stall > 0 {
+            self.cycle += stall as u64;
+            self.mem.tick(stall);
+        }
+    }
+
+    /// Writes the instruction about to run to the trace and trace log
+    fn trace_instruction(&mut self) {
+        if self.trace.is_some() {
+            let line = self.trace_line();
+            if let Err(e) = writeln!(self.trace.as_mut().unwrap(), "{}", line) {
+                warn!("Can not write the trace, disabling it: {}", e);
+                self.trace = None;
+            }
+        }
+        if tracelog::enabled() {
+            let mem = &self.mem;
+            let instruction = disasm::decode(self.pc, |addr| mem.peek8(addr));
+            trace!(
+                "{}: {}",
+                mem.symbols().describe(mem.bank_at(self.pc), self.pc),
+                instruction.labelled(mem.symbols(), |addr| mem.bank_at(addr))
+            );
+            trace!("{:04x?}", self);
+        }
     }
 
+    /// Executes an opcode already fetched, every memory access and internal
+    /// delay advances the system by one M-cycle as it happens
     fn execute(&mut self, op: u8) {
-        self.cycle += OP_CYCLES[op as usize] as u64;
         match op {
             0x0 => {
                 /* NOP */
@@ -164,32 +388,81 @@ impl Cpu {
                 let d16 = self.fetch16();
                 self.bc.set(d16)
             }
-            // 0x02 => {
-            //     [>LD (BC), A <]
-
-
-            // }
+            0x02 => {
+                /* LD (BC), A
+                 *  1  8 */
+                self.write8(self.bc.get(), self.af.0);
+            }
+            0x10 => {
+                /* STOP 0
+                 *  2  4 */
+                // The second byte is skipped without a bus cycle
+                self.pc = self.pc.wrapping_add(1);
+                if !self.mem.switch_speed() {
+                    warn!("STOP without a speed switch is not supported, ignoring");
+                }
+            }
             0x19 => {
                 /*  ADD HL, DE 
                  *    1  8 
                  *  - 0 H C */
                 let (res, carry) = self.de.get().overflowing_add(self.hl.get());
                 let (_, half_carry) = self.hl.1.overflowing_add(self.de.1);
+                self.idle();
                 self.hl.set(res);
                 self.set_flag_substract(false);
                 self.set_flag_half_carry(half_carry);
                 self.set_flag_carry(carry);
             }
+            0x20 | 0x28 | 0x30 | 0x38 => {
+                /* JR cc, r8
+                 *  2  12/8 */
+                let r8 = self.fetch() as i8;
+                if self.condition(op) {
+                    self.idle();
+                    self.jump(self.pc.wrapping_add(r8 as u16));
+                }
+            }
             0x21 => {
                 /* LD HL, d16 */
                 let a16 = self.fetch16();
                 self.hl.set(a16);
             }
+            0xc0 | 0xc8 | 0xd0 | 0xd8 => {
+                /* RET cc
+                 *  1  20/8 */
+                // The condition is checked on an internal M-cycle
+                self.idle();
+                if self.condition(op) {
+                    let addr = self.stack_pop16();
+                    self.idle();
+                    self.jump(addr);
+                }
+            }
+            0xc2 | 0xca | 0xd2 | 0xda => {
+                /* JP cc, a16
+                 *  3  16/12 */
+                let a16 = self.fetch16();
+                if self.condition(op) {
+                    self.idle();
+                    self.jump(a16);
+                }
+            }
             0xc3 => {
                 /* JP a16 */
                 let a16 = self.fetch16();
+                self.idle();
                 self.jump(a16);
             }
+            0xc4 | 0xcc | 0xd4 | 0xdc => {
+                /* CALL cc, a16
+                 *  3  24/12 */
+                let a16 = self.fetch16();
+                if self.condition(op) {
+                    self.stack_push16(self.pc);
+                    self.jump(a16);
+                }
+            }
             0xcd => {
                 /* CALL a16 */
                 let a16 = self.fetch16();
@@ -207,39 +480,83 @@ impl Cpu {
                  */
                 let a8 = self.fetch();
                 let addr = 0xFF00 + a8 as u16;
-                self.mem.write(addr, self.af.0);
+                self.write8(addr, self.af.0);
             }
             0xf0 => {
                 /*LDH A, (a8) 
                  *  2  12 
                  */
                 let a8 = self.fetch();
-                let val = self.mem.read8(0xFF00 + a8 as u16);
+                let val = self.read8(0xFF00 + a8 as u16);
                 self.af.0 = val;
             }
+            0xd3 | 0xdb | 0xdd | 0xe3 | 0xe4 | 0xeb | 0xec | 0xed | 0xf4 | 0xfc | 0xfd => {
+                /* Illegal opcodes hang the cpu */
+                let lockup = Lockup {
+                    op,
+                    addr: self.pc.wrapping_sub(1),
+                };
+                warn!("{}", lockup);
+                self.lockup = Some(lockup);
+            }
             _ => panic!("This op code is not supported yet! opcode: 0x{:x}", op),
         }
     }
 
+    /// Takes an internal M-cycle to decrement SP, then writes the high byte first
     fn stack_push16(&mut self, val: u16) {
-        self.sp -= 2;
-        self.mem.write16(self.sp, val);
+        self.idle();
+        self.sp = self.sp.wrapping_sub(1);
+        self.write8(self.sp, (val >> 8) as u8);
+        self.sp = self.sp.wrapping_sub(1);
+        self.write8(self.sp, val as u8);
+    }
+
+    fn stack_pop16(&mut self) -> u16 {
+        let low = self.read8(self.sp) as u16;
+        self.sp = self.sp.wrapping_add(1);
+        let high = self.read8(self.sp) as u16;
+        self.sp = self.sp.wrapping_add(1);
+        (high << 8) | low
+    }
+
+    /// An M-cycle without a memory access
+    fn idle(&mut self) {
+        self.cycle += 4;
+        self.mem.tick(4);
+    }
+
+    /// Reads at the end of an M-cycle, after the rest of the system advanced
+    fn read8(&mut self, addr: u16) -> u8 {
+        self.idle();
+        self.mem.sync(addr);
+        self.mem.read8(addr)
+    }
+
+    /// Writes at the end of an M-cycle, after the rest of the system advanced
+    fn write8(&mut self, addr: u16, val: u8) {
+        self.idle();
+        self.mem.write(addr, val);
     }
 
+    /// Reads the next instruction byte, unlike `read8` it does not trigger
+    /// read watchpoints
     fn fetch(&mut self) -> u8 {
-        let val = self.mem.read8(self.pc);
-        self.pc += 1;
+        self.idle();
+        self.mem.sync(self.pc);
+        let val = self.mem.fetch8(self.pc);
+        self.pc = self.pc.wrapping_add(1);
         val
     }
 
     fn fetch16(&mut self) -> u16 {
-        let val = self.mem.read16(self.pc);
-        self.pc += 2;
-        val
+        let low = self.fetch() as u16;
+        let high = self.fetch() as u16;
+        (high << 8) | low
     }
 
     fn jump(&mut self, addr: u16) {
-        trace!("Jumping to 0x{:X}", addr);
+        trace_event!("Jumping to 0x{:X}", addr);
         self.pc = addr;
     }
 }
@@ -257,10 +574,21 @@ mod tests {
         env::{self},
         fs::File,
         io::{self, Write},
+        sync::atomic::{AtomicUsize, Ordering},
     };
 
-    fn create_rom_file(bytes: Vec<u8>) -> std::io::Result<String> {
-        let filepath = env::temp_dir().join("uboy_tmp_gameboy_rom.gb");
+    static ROM_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+    fn create_rom_file(mut bytes: Vec<u8>) -> std::io::Result<String> {
+        // Tests run in parallel, every rom gets its own file
+        let count = ROM_COUNT.fetch_add(1, Ordering::SeqCst);
+        let filepath = env::temp_dir().join(format!(
+            "uboy_tmp_gameboy_rom_{}_{}.gb",
+            std::process::id(),
+            count
+        ));
+        // Pad to a whole 32Kb rom so the cartridge header can be parsed
+        bytes.resize(0x8000, 0);
         let mut file = File::create(filepath.clone())?;
         file.write_all(&bytes)?;
         Ok(String::from(filepath.to_str().unwrap()))
@@ -270,7 +598,8 @@ mod tests {
     fn nop() {
         let mem = Memory::default();
         let mut cpu = Cpu::new(mem);
-        cpu.execute(0x0);
+        let op = cpu.fetch();
+        cpu.execute(op);
         assert!(cpu.af == RegPair::from(0));
         assert!(cpu.bc == RegPair::from(0));
         assert!(cpu.de == RegPair::from(0));
@@ -285,10 +614,11 @@ mod tests {
         let mem = Memory::default();
         let mut cpu = Cpu::new(mem);
         cpu.mem
-            .load_rom(&create_rom_file(vec![0x1u8, 0xfu8, 0xeu8])?);
-        cpu.execute(0x1);
+            .load_rom(&create_rom_file(vec![0x1u8, 0xfu8, 0xeu8])?)?;
+        let op = cpu.fetch();
+        cpu.execute(op);
         assert!(cpu.af == RegPair::from(0));
-        assert!(cpu.bc == RegPair::from((0xf << 8) | 0xe));
+        assert!(cpu.bc == RegPair::from((0xe << 8) | 0xf));
         assert!(cpu.de == RegPair::from(0));
         assert!(cpu.hl == RegPair::from(0));
         assert!(cpu.sp == 0);
@@ -302,18 +632,200 @@ mod tests {
         let mem = Memory::default();
         let mut cpu = Cpu::new(mem);
         cpu.mem
-            .load_rom(&create_rom_file(vec![0x2u8, 0xfu8, 0xeu8])?);
+            .load_rom(&create_rom_file(vec![0x2u8, 0xfu8, 0xeu8])?)?;
         cpu.bc = RegPair::from(0xC000);
         cpu.af.0 = 0xf;
-        cpu.execute(0x1);
+        let op = cpu.fetch();
+        cpu.execute(op);
         assert!(cpu.af == RegPair::from(0xf00));
         assert!(cpu.bc == RegPair::from(0xc000));
         assert!(cpu.de == RegPair::from(0));
         assert!(cpu.hl == RegPair::from(0));
         assert!(cpu.sp == 0);
-        assert!(cpu.pc == 3);
-        assert!(cpu.cycle == 12);
-        assert!(cpu.mem.read8(0xC00) == cpu.af.0);
+        assert!(cpu.pc == 1);
+        assert!(cpu.cycle == 8);
+        assert!(cpu.mem.read8(0xC000) == cpu.af.0);
+        Ok(())
+    }
+
+    #[test]
+    fn stop_switches_speed() -> io::Result<()> {
+        let mem = Memory::default();
+        let mut cpu = Cpu::new(mem);
+        cpu.mem.load_rom(&create_rom_file(vec![0x10u8, 0x00u8])?)?;
+        cpu.mem.set_cgb(true);
+        cpu.mem.write(0xFF4D, 0x01);
+        let op = cpu.fetch();
+        cpu.execute(op);
+        assert!(cpu.pc == 2);
+        assert!(cpu.cycle == 4);
+        assert!(cpu.mem.read8(0xFF4D) == 0xFE);
+        Ok(())
+    }
+
+    #[test]
+    fn op_cycles_match_execution() -> io::Result<()> {
+        use std::panic::{self, AssertUnwindSafe};
+        for op in 0..=0xFF {
+            // Both flag states, so conditional branches are taken once
+            for &flags in &[0x00, 0xF0] {
+                let mut rom = vec![op, 0x10, 0x20, 0x30];
+                rom.resize(0x8000, 0);
+                let mut mem = Memory::default();
+                mem.load_rom_bytes(rom)?;
+                let mut cpu = Cpu::new(mem);
+                cpu.af.1 = flags;
+                cpu.sp = 0xD000;
+                let taken = branch_cycles(op).filter(|_| cpu.condition(op));
+                let step = panic::catch_unwind(AssertUnwindSafe(|| cpu.step()));
+                if step.is_err() || cpu.lockup().is_some() {
+                    // Not implemented yet or illegal
+                    continue;
+                }
+                let expected = taken.unwrap_or(OP_CYCLES[op as usize]);
+                assert!(
+                    cpu.cycle == expected as u64,
+                    "opcode {:02X} took {} cycles instead of {}",
+                    op,
+                    cpu.cycle,
+                    expected
+                );
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn fetches_do_not_trigger_watchpoints() -> io::Result<()> {
+        use crate::watch::{Access, Watchpoint};
+        let mut cpu = Cpu::new(Memory::default());
+        cpu.mem.load_rom(&create_rom_file(vec![0xF0, 0x80])?)?;
+        for (start, end) in [(0x0000, 0x0001), (0xFF80, 0xFF80)] {
+            cpu.mem.add_watchpoint(Watchpoint {
+                start,
+                end,
+                access: Access::Read,
+                value: None,
+            });
+        }
+        cpu.step();
+        // Only the read of LDH A, (a8), not the opcode or its operand
+        let hit = cpu.mem.take_watch_hit().unwrap();
+        assert!(hit.index == 1 && hit.addr == 0xFF80);
+        Ok(())
+    }
+
+    #[test]
+    fn memory_access_timing() -> io::Result<()> {
+        // LD (BC), A resets DIV on its second M-cycle, LDH A, (a8) reads it
+        // on its third, so 61 NOPs in between make exactly 256 cycles
+        for (nops, div) in [(60, 0), (61, 1)] {
+            let mut rom = vec![0x01, 0x04, 0xFF, 0x02];
+            rom.extend(vec![0x00; nops]);
+            rom.extend([0xF0, 0x04]);
+            let mut cpu = Cpu::new(Memory::default());
+            cpu.mem.load_rom(&create_rom_file(rom)?)?;
+            for _ in 0..nops + 3 {
+                cpu.step();
+            }
+            assert!(cpu.af.0 == div);
+            assert!(cpu.cycle == 12 + 8 + 4 * nops as u64 + 12);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn conditional_branches() -> io::Result<()> {
+        // Opcodes with their not taken and taken cycles and pc
+        let groups = [
+            ([0x20, 0x28, 0x30, 0x38], (8, 0x0002), (12, 0x0012)),
+            ([0xC2, 0xCA, 0xD2, 0xDA], (12, 0x0003), (16, 0x2010)),
+            ([0xC4, 0xCC, 0xD4, 0xDC], (12, 0x0003), (24, 0x2010)),
+            ([0xC0, 0xC8, 0xD0, 0xD8], (8, 0x0001), (20, 0x1234)),
+        ];
+        for (ops, not_taken, taken) in groups.iter() {
+            for &op in ops {
+                assert!(OP_CYCLES[op as usize] == not_taken.0);
+                assert!(branch_cycles(op) == Some(taken.0));
+                let rom = create_rom_file(vec![op, 0x10, 0x20])?;
+                for &take in &[false, true] {
+                    let mut cpu = Cpu::new(Memory::default());
+                    cpu.mem.load_rom(&rom)?;
+                    cpu.mem.write(0xD000, 0x34);
+                    cpu.mem.write(0xD001, 0x12);
+                    cpu.sp = 0xD000;
+                    // NZ and NC are taken with the flag clear, Z and C with it set
+                    let flag = if op & 0x10 == 0 { 0x80 } else { 0x10 };
+                    if take == (op & 0x08 != 0) {
+                        cpu.af.1 = flag;
+                    }
+                    cpu.step();
+                    let (cycles, pc) = if take { *taken } else { *not_taken };
+                    assert!(cpu.cycle == cycles as u64, "{:02X} {}", op, take);
+                    assert!(cpu.pc == pc, "{:02X} {}", op, take);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn call_and_ret_use_the_stack() -> io::Result<()> {
+        // CALL NZ, $0010; at 0010 RET Z with Z clear, then RET NZ
+        let mut rom = vec![0xC4, 0x10, 0x00];
+        rom.resize(0x10, 0);
+        rom.extend([0xC8, 0xC0]);
+        let mut cpu = Cpu::new(Memory::default());
+        cpu.mem.load_rom(&create_rom_file(rom)?)?;
+        cpu.sp = 0xD000;
+        cpu.step();
+        assert!(cpu.sp == 0xCFFE);
+        assert!(cpu.mem.read8(0xCFFE) == 0x03 && cpu.mem.read8(0xCFFF) == 0x00);
+        cpu.step();
+        cpu.step();
+        assert!(cpu.pc == 0x0003);
+        assert!(cpu.sp == 0xD000);
+        assert!(cpu.cycle == 24 + 8 + 20);
+        Ok(())
+    }
+
+    #[test]
+    fn illegal_opcodes_lock_up() -> io::Result<()> {
+        for &op in &[0xD3, 0xDB, 0xDD, 0xE3, 0xE4, 0xEB, 0xEC, 0xED, 0xF4, 0xFC, 0xFD] {
+            let mut cpu = Cpu::new(Memory::default());
+            cpu.mem.load_rom(&create_rom_file(vec![0x00, op])?)?;
+            cpu.step();
+            assert!(cpu.lockup().is_none());
+            cpu.step();
+            assert!(cpu.lockup() == Some(Lockup { op, addr: 1 }));
+            // Time keeps passing without anything being executed
+            cpu.run_frames(1);
+            assert!(cpu.pc == 2);
+            assert!(cpu.mem.frame() == 1);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn doctor_trace() -> io::Result<()> {
+        let mut rom = vec![0; 0x104];
+        rom[0x100..0x104].copy_from_slice(&[0x00, 0xC3, 0x13, 0x02]);
+        let romname = create_rom_file(rom)?;
+        let mut mem = Memory::default();
+        let cart = mem.load_rom(&romname)?;
+        mem.set_ly_stub(true);
+        let mut cpu = Cpu::new(mem);
+        cpu.initialize(&cart);
+        let trace_path = format!("{}.trace", romname);
+        cpu.set_trace(Box::new(File::create(&trace_path)?));
+        cpu.step();
+        cpu.flush_trace()?;
+        assert!(
+            std::fs::read_to_string(&trace_path)?
+                == "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,13,02\n"
+        );
+        assert!(cpu.trace_line().ends_with("PC:0101 PCMEM:C3,13,02,00"));
+        assert!(cpu.mem.read8(0xFF44) == 0x90);
         Ok(())
     }
 }
@@ -0,0 +1,564 @@
+use crate::cpu::{Cpu, Reg};
+use crate::disasm;
+use crate::watch::{Access, Watchpoint};
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use std::{
+    io::{self, BufRead, Write},
+    panic::{self, AssertUnwindSafe},
+};
+
+const HELP: &str = "\
+Commands, values are hex and addresses hex or labels:
+  s, step [N]             execute N instructions (default 1)
+  c, continue             run until a breakpoint is hit
+  b, break ADDR           break when PC reaches ADDR
+  b, break op XX          break before executing opcode XX
+  b, break mem ADDR [XX]  break when the byte at ADDR changes or becomes XX
+  bl, breakpoints         list breakpoints
+  d, delete N             delete breakpoint number N
+  wa, watch [r|w|rw] ADDR[-END] [XX]
+                          break when the range is read, written or both
+                          (default w), optionally only for the value XX,
+                          fetching instructions is not a read
+  wl, watches             list watchpoints
+  wd, unwatch N           delete watchpoint number N
+  r, regs                 show registers and flags
+  l, list [ADDR] [N]      disassemble N instructions (default 10) from ADDR or PC
+  x ADDR [LEN]            hexdump LEN bytes (default 40) from ADDR
+  set REG VAL             set a register (a f b c d e h l af bc de hl sp pc)
+  w ADDR XX [XX ...]      write bytes to memory starting at ADDR
+  q, quit                 stop emulation
+An empty line repeats the last command.";
+
+enum Breakpoint {
+    Pc(u16),
+    Opcode(u8),
+    Memory {
+        addr: u16,
+        value: Option<u8>, /* break on any change when unset */
+        last: u8,
+    },
+}
+
+impl Breakpoint {
+    /// Checks the breakpoint against the state before the next instruction
+    fn hit(&mut self, cpu: &Cpu) -> bool {
+        let pc = cpu.reg(Reg::PC);
+        match self {
+            Breakpoint::Pc(addr) => pc == *addr,
+            Breakpoint::Opcode(op) => cpu.mem().peek8(pc) == *op,
+            Breakpoint::Memory { addr, value, last } => {
+                let current = cpu.mem().peek8(*addr);
+                let changed = current != *last;
+                *last = current;
+                match value {
+                    Some(value) => changed && current == *value,
+                    None => changed,
+                }
+            }
+        }
+    }
+
+    fn describe(&self, cpu: &Cpu) -> String {
+        match self {
+            Breakpoint::Pc(addr) => format!("pc {}", location(cpu, *addr)),
+            Breakpoint::Opcode(op) => format!("opcode {:02X}", op),
+            Breakpoint::Memory {
+                addr,
+                value: Some(value),
+                ..
+            } => format!("mem {:04X} == {:02X}", addr, value),
+            Breakpoint::Memory { addr, .. } => format!("mem {:04X} changes", addr),
+        }
+    }
+}
+
+/// Interactive debugger reading commands from a line based input
+#[derive(Default)]
+pub struct Debugger {
+    breakpoints: Vec<Breakpoint>,
+    last_command: String,
+}
+
+impl Debugger {
+    /// Runs the command loop until `quit` or the end of the input
+    pub fn repl(
+        &mut self,
+        cpu: &mut Cpu,
+        input: impl BufRead,
+        mut out: impl Write,
+    ) -> io::Result<()> {
+        self.show_regs(cpu, &mut out)?;
+        write!(out, "(uboy) ")?;
+        out.flush()?;
+        for line in input.lines() {
+            let line = line?;
+            let line = if line.trim().is_empty() {
+                self.last_command.clone()
+            } else {
+                line
+            };
+            match self.command(cpu, &line, &mut out) {
+                Ok(true) => {}
+                Ok(false) => return Ok(()),
+                Err(e) if e.kind() == io::ErrorKind::InvalidInput => writeln!(out, "{}", e)?,
+                Err(e) => return Err(e),
+            }
+            self.last_command = line;
+            write!(out, "(uboy) ")?;
+            out.flush()?;
+        }
+        writeln!(out)
+    }
+
+    /// Executes one command, returns false when the debugger should quit
+    pub fn command(&mut self, cpu: &mut Cpu, line: &str, out: &mut impl Write) -> io::Result<bool> {
+        let args: Vec<&str> = line.split_whitespace().collect();
+        let arg = |index: usize| {
+            args.get(index)
+                .copied()
+                .ok_or_else(|| invalid(format!("'{}' needs more arguments", args[0])))
+        };
+        match args.first().copied().unwrap_or("") {
+            "" => {}
+            "s" | "step" => {
+                let count = match args.get(1) {
+                    Some(count) => count
+                        .parse()
+                        .map_err(|_| invalid(format!("'{}' is not a count", count)))?,
+                    None => 1,
+                };
+                self.execute(cpu, Some(count), out)?;
+            }
+            "c" | "continue" => self.execute(cpu, None, out)?,
+            "b" | "break" => {
+                let breakpoint = match arg(1)? {
+                    "op" => Breakpoint::Opcode(parse_hex(arg(2)?)? as u8),
+                    "mem" => {
+                        let addr = parse_addr(cpu, arg(2)?)?;
+                        let value = match args.get(3) {
+                            Some(value) => Some(parse_hex(value)? as u8),
+                            None => None,
+                        };
+                        let last = cpu.mem().peek8(addr);
+                        Breakpoint::Memory { addr, value, last }
+                    }
+                    addr => Breakpoint::Pc(parse_addr(cpu, addr)?),
+                };
+                writeln!(
+                    out,
+                    "Breakpoint {} at {}",
+                    self.breakpoints.len(),
+                    breakpoint.describe(cpu)
+                )?;
+                self.breakpoints.push(breakpoint);
+            }
+            "bl" | "breakpoints" => {
+                if self.breakpoints.is_empty() {
+                    writeln!(out, "No breakpoints")?;
+                }
+                for (number, breakpoint) in self.breakpoints.iter().enumerate() {
+                    writeln!(out, "{}: {}", number, breakpoint.describe(cpu))?;
+                }
+            }
+            "d" | "delete" => {
+                let number: usize = arg(1)?
+                    .parse()
+                    .map_err(|_| invalid(format!("'{}' is not a breakpoint number", args[1])))?;
+                if number >= self.breakpoints.len() {
+                    return Err(invalid(format!("No breakpoint {}", number)));
+                }
+                self.breakpoints.remove(number);
+            }
+            "wa" | "watch" => {
+                let (access, range) = match arg(1)? {
+                    "r" => (Access::Read, 2),
+                    "w" => (Access::Write, 2),
+                    "rw" => (Access::ReadWrite, 2),
+                    _ => (Access::Write, 1),
+                };
+                let (start, end) = match arg(range)?.split_once('-') {
+                    Some((start, end)) => (parse_addr(cpu, start)?, parse_addr(cpu, end)?),
+                    None => {
+                        let addr = parse_addr(cpu, args[range])?;
+                        (addr, addr)
+                    }
+                };
+                if end < start {
+                    return Err(invalid(format!("'{}' is an empty range", args[range])));
+                }
+                let value = match args.get(range + 1) {
+                    Some(value) => Some(parse_hex(value)? as u8),
+                    None => None,
+                };
+                let watchpoint = Watchpoint {
+                    start,
+                    end,
+                    access,
+                    value,
+                };
+                let index = cpu.mem_mut().add_watchpoint(watchpoint);
+                writeln!(out, "Watchpoint {} on {}", index, watchpoint)?;
+            }
+            "wl" | "watches" => {
+                if cpu.mem().watchpoints().is_empty() {
+                    writeln!(out, "No watchpoints")?;
+                }
+                for (number, watchpoint) in cpu.mem().watchpoints().iter().enumerate() {
+                    writeln!(out, "{}: {}", number, watchpoint)?;
+                }
+            }
+            "wd" | "unwatch" => {
+                let number: usize = arg(1)?
+                    .parse()
+                    .map_err(|_| invalid(format!("'{}' is not a watchpoint number", args[1])))?;
+                if cpu.mem_mut().remove_watchpoint(number).is_none() {
+                    return Err(invalid(format!("No watchpoint {}", number)));
+                }
+            }
+            "r" | "regs" => self.show_regs(cpu, out)?,
+            "l" | "list" => {
+                let mut addr = match args.get(1) {
+                    Some(addr) => parse_addr(cpu, addr)?,
+                    None => cpu.reg(Reg::PC),
+                };
+                let count = match args.get(2) {
+                    Some(count) => count
+                        .parse()
+                        .map_err(|_| invalid(format!("'{}' is not a count", count)))?,
+                    None => 10,
+                };
+                for _ in 0..count {
+                    addr = addr.wrapping_add(write_listing(cpu, addr, out)?);
+                }
+            }
+            "x" => {
+                let addr = parse_addr(cpu, arg(1)?)?;
+                let len = match args.get(2) {
+                    Some(len) => parse_hex(len)?,
+                    None => 0x40,
+                };
+                hexdump(cpu, addr, len, out)?;
+            }
+            "set" => {
+                let val = parse_hex(arg(2)?)?;
+                set_register(cpu, arg(1)?, val)?;
+                self.show_regs(cpu, out)?;
+            }
+            "w" => {
+                let addr = parse_addr(cpu, arg(1)?)?;
+                arg(2)?;
+                for (offset, val) in args[2..].iter().enumerate() {
+                    let val = parse_hex(val)? as u8;
+                    cpu.mem_mut().poke8(addr.wrapping_add(offset as u16), val);
+                }
+            }
+            "h" | "help" => writeln!(out, "{}", HELP)?,
+            "q" | "quit" => return Ok(false),
+            other => return Err(invalid(format!("Unknown command '{}', try 'help'", other))),
+        }
+        Ok(true)
+    }
+
+    /// Runs `count` instructions or until a break or watchpoint is hit. The first
+    /// instruction always runs, so continuing from a breakpoint works.
+    fn execute(
+        &mut self,
+        cpu: &mut Cpu,
+        count: Option<u64>,
+        out: &mut impl Write,
+    ) -> io::Result<()> {
+        let mut executed = 0;
+        while count.map_or(true, |count| executed < count) {
+            let pc = cpu.reg(Reg::PC);
+            let op = cpu.mem().peek8(pc);
+            if panic::catch_unwind(AssertUnwindSafe(|| cpu.step())).is_err() {
+                writeln!(
+                    out,
+                    "Emulation crashed executing the instruction at {}",
+                    location(cpu, pc)
+                )?;
+                break;
+            }
+            executed += 1;
+            if let Some(lockup) = cpu.lockup() {
+                writeln!(
+                    out,
+                    "CPU locked up by illegal opcode {:02X} at {}",
+                    lockup.op,
+                    location(cpu, lockup.addr)
+                )?;
+                break;
+            }
+            if let Some(hit) = cpu.mem_mut().take_watch_hit() {
+                writeln!(
+                    out,
+                    "Watchpoint {} hit, {} {:02X} at {} by the instruction at {} ({:02X})",
+                    hit.index,
+                    if hit.write { "wrote" } else { "read" },
+                    hit.value,
+                    location(cpu, hit.addr),
+                    location(cpu, pc),
+                    op
+                )?;
+                break;
+            }
+            // Every breakpoint is checked so memory breakpoints track the value
+            let hits: Vec<usize> = (0..self.breakpoints.len())
+                .filter(|index| self.breakpoints[*index].hit(cpu))
+                .collect();
+            if let Some(index) = hits.first() {
+                writeln!(
+                    out,
+                    "Breakpoint {} hit, {}",
+                    index,
+                    self.breakpoints[*index].describe(cpu)
+                )?;
+                break;
+            }
+        }
+        // Components run lazily, catch them up before they are inspected
+        cpu.mem_mut().sync_all();
+        self.show_regs(cpu, out)
+    }
+
+    fn show_regs(&self, cpu: &Cpu, out: &mut impl Write) -> io::Result<()> {
+        let flags = cpu.reg(Reg::AF) as u8;
+        let flag = |bit: u8, name: char| if flags & (1 << bit) != 0 { name } else { '-' };
+        let pc = cpu.reg(Reg::PC);
+        writeln!(
+            out,
+            "AF={:04X} BC={:04X} DE={:04X} HL={:04X} SP={:04X} PC={:04X} [{}{}{}{}] cycle {}",
+            cpu.reg(Reg::AF),
+            cpu.reg(Reg::BC),
+            cpu.reg(Reg::DE),
+            cpu.reg(Reg::HL),
+            cpu.reg(Reg::SP),
+            pc,
+            flag(7, 'Z'),
+            flag(6, 'N'),
+            flag(5, 'H'),
+            flag(4, 'C'),
+            cpu.cycle()
+        )?;
+        write_listing(cpu, pc, out)?;
+        Ok(())
+    }
+}
+
+/// Address followed by the closest label
+fn location(cpu: &Cpu, addr: u16) -> String {
+    cpu.mem().symbols().describe(cpu.mem().bank_at(addr), addr)
+}
+
+/// Writes the instruction at `addr` and the label on it, returns its length
+fn write_listing(cpu: &Cpu, addr: u16, out: &mut impl Write) -> io::Result<u16> {
+    let mem = cpu.mem();
+    if let Some(label) = mem.symbols().label(mem.bank_at(addr), addr) {
+        writeln!(out, "{}:", label)?;
+    }
+    let instruction = disasm::decode(addr, |addr| mem.peek8(addr));
+    writeln!(
+        out,
+        "{}",
+        instruction.listing(mem.symbols(), |addr| mem.bank_at(addr))
+    )?;
+    Ok(instruction.len())
+}
+
+/// Parses a label or a hex address
+fn parse_addr(cpu: &Cpu, val: &str) -> io::Result<u16> {
+    match cpu.mem().symbols().find(val) {
+        Some((_, addr)) => Ok(addr),
+        None => parse_hex(val),
+    }
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+/// Parses a hex number, with or without a `0x` or `$` prefix
+fn parse_hex(val: &str) -> io::Result<u16> {
+    let digits = val
+        .trim_start_matches("0x")
+        .trim_start_matches("0X")
+        .trim_start_matches('$');
+    u16::from_str_radix(digits, 16).map_err(|_| invalid(format!("'{}' is not a hex number", val)))
+}
+
+fn set_register(cpu: &mut Cpu, name: &str, val: u16) -> io::Result<()> {
+    let (reg, half) = match name.to_ascii_lowercase().as_str() {
+        "af" => (Reg::AF, None),
+        "bc" => (Reg::BC, None),
+        "de" => (Reg::DE, None),
+        "hl" => (Reg::HL, None),
+        "sp" => (Reg::SP, None),
+        "pc" => (Reg::PC, None),
+        "a" => (Reg::AF, Some(true)),
+        "f" => (Reg::AF, Some(false)),
+        "b" => (Reg::BC, Some(true)),
+        "c" => (Reg::BC, Some(false)),
+        "d" => (Reg::DE, Some(true)),
+        "e" => (Reg::DE, Some(false)),
+        "h" => (Reg::HL, Some(true)),
+        "l" => (Reg::HL, Some(false)),
+        _ => return Err(invalid(format!("Unknown register '{}'", name))),
+    };
+    let current = cpu.reg(reg);
+    let val = match half {
+        None => val,
+        Some(true) => (current & 0x00FF) | (val & 0xFF) << 8,
+        Some(false) => (current & 0xFF00) | (val & 0xFF),
+    };
+    cpu.set_reg(reg, val);
+    Ok(())
+}
+
+/// Dumps memory with `Memory::peek8`, which does not change any state
+fn hexdump(cpu: &Cpu, addr: u16, len: u16, out: &mut impl Write) -> io::Result<()> {
+    let bytes: Vec<u8> = (0..len)
+        .map(|offset| cpu.mem().peek8(addr.wrapping_add(offset)))
+        .collect();
+    for (row, chunk) in bytes.chunks(16).enumerate() {
+        let hex: Vec<String> = chunk.iter().map(|byte| format!("{:02X}", byte)).collect();
+        let text: String = chunk
+            .iter()
+            .map(|byte| {
+                if byte.is_ascii_graphic() {
+                    *byte as char
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+        writeln!(
+            out,
+            "{:04X}: {:<47}  {}",
+            addr.wrapping_add(row as u16 * 16),
+            hex.join(" "),
+            text
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem::Memory;
+    use crate::symbols::Symbols;
+
+    fn run(debugger: &mut Debugger, cpu: &mut Cpu, line: &str) -> String {
+        let mut out = Vec::new();
+        debugger.command(cpu, line, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn step_and_breakpoints() {
+        // Memory::default() has an all zero rom, so every instruction is a NOP
+        let mut cpu = Cpu::new(Memory::default());
+        let mut debugger = Debugger::default();
+        run(&mut debugger, &mut cpu, "step 3");
+        assert!(cpu.reg(Reg::PC) == 3);
+        run(&mut debugger, &mut cpu, "break 10");
+        let out = run(&mut debugger, &mut cpu, "continue");
+        assert!(out.starts_with("Breakpoint 0 hit, pc 0010"));
+        assert!(cpu.reg(Reg::PC) == 0x10);
+        // The breakpoint is left before it can be hit again
+        run(&mut debugger, &mut cpu, "d 0");
+        run(&mut debugger, &mut cpu, "b 12");
+        run(&mut debugger, &mut cpu, "step 5");
+        assert!(cpu.reg(Reg::PC) == 0x12);
+    }
+
+    #[test]
+    fn memory_breakpoint() {
+        let mut cpu = Cpu::new(Memory::default());
+        let mut debugger = Debugger::default();
+        run(&mut debugger, &mut cpu, "break mem c000 42");
+        run(&mut debugger, &mut cpu, "w c000 41");
+        run(&mut debugger, &mut cpu, "step");
+        run(&mut debugger, &mut cpu, "w c000 42");
+        let out = run(&mut debugger, &mut cpu, "step 10");
+        assert!(out.starts_with("Breakpoint 0 hit, mem C000 == 42"));
+        assert!(cpu.reg(Reg::PC) == 2);
+    }
+
+    #[test]
+    fn lockup_stops_execution() {
+        let mut cpu = Cpu::new(Memory::default());
+        let mut debugger = Debugger::default();
+        run(&mut debugger, &mut cpu, "w c002 db");
+        run(&mut debugger, &mut cpu, "set pc c000");
+        let out = run(&mut debugger, &mut cpu, "continue");
+        assert!(out.starts_with("CPU locked up by illegal opcode DB at C002"));
+        // Stepping a locked up cpu reports it again
+        let out = run(&mut debugger, &mut cpu, "step");
+        assert!(out.starts_with("CPU locked up"));
+        assert!(cpu.reg(Reg::PC) == 0xC003);
+    }
+
+    #[test]
+    fn modify_and_dump() {
+        let mut cpu = Cpu::new(Memory::default());
+        let mut debugger = Debugger::default();
+        run(&mut debugger, &mut cpu, "set hl 1234");
+        run(&mut debugger, &mut cpu, "set a $ff");
+        run(&mut debugger, &mut cpu, "set f ff");
+        assert!(cpu.reg(Reg::HL) == 0x1234);
+        assert!(cpu.reg(Reg::AF) == 0xFFF0);
+        run(&mut debugger, &mut cpu, "w c000 48 49");
+        let out = run(&mut debugger, &mut cpu, "x c000 2");
+        assert!(out == format!("C000: {:<47}  HI\n", "48 49"));
+        let mut out = Vec::new();
+        assert!(debugger.command(&mut cpu, "set q 1", &mut out).is_err());
+    }
+
+    #[test]
+    fn watchpoint_reports_instruction() {
+        let mut cpu = Cpu::new(Memory::default());
+        let mut debugger = Debugger::default();
+        // NOP, LD (BC),A in work ram
+        run(&mut debugger, &mut cpu, "w c000 00 02");
+        run(&mut debugger, &mut cpu, "set pc c000");
+        run(&mut debugger, &mut cpu, "set bc c0a0");
+        run(&mut debugger, &mut cpu, "set a 42");
+        run(&mut debugger, &mut cpu, "watch w c000-c0ff 42");
+        let out = run(&mut debugger, &mut cpu, "continue");
+        assert!(
+            out.starts_with("Watchpoint 0 hit, wrote 42 at C0A0 by the instruction at C001 (02)")
+        );
+        assert!(cpu.reg(Reg::PC) == 0xC002);
+        run(&mut debugger, &mut cpu, "wd 0");
+        assert!(run(&mut debugger, &mut cpu, "wl") == "No watchpoints\n");
+    }
+
+    #[test]
+    fn poking_memory_does_not_hit_watchpoints() {
+        let mut cpu = Cpu::new(Memory::default());
+        let mut debugger = Debugger::default();
+        run(&mut debugger, &mut cpu, "watch rw c000");
+        run(&mut debugger, &mut cpu, "w c000 42");
+        assert!(cpu.mem().peek8(0xC000) == 0x42);
+        let out = run(&mut debugger, &mut cpu, "step");
+        assert!(!out.contains("Watchpoint"));
+        assert!(cpu.reg(Reg::PC) == 1);
+    }
+
+    #[test]
+    fn labels() {
+        let mut cpu = Cpu::new(Memory::default());
+        cpu.mem_mut()
+            .set_symbols(Symbols::parse("00:0010 Start\n00:C000 wCounter\n"));
+        let mut debugger = Debugger::default();
+        assert!(run(&mut debugger, &mut cpu, "break Start") == "Breakpoint 0 at pc 0010 <Start>\n");
+        let out = run(&mut debugger, &mut cpu, "continue");
+        assert!(out.starts_with("Breakpoint 0 hit, pc 0010 <Start>"));
+        assert!(out.ends_with("Start:\n0010: 00        NOP\n"));
+        run(&mut debugger, &mut cpu, "w wCounter 7");
+        assert!(cpu.mem().peek8(0xC000) == 7);
+    }
+}
@@ -0,0 +1,353 @@
+use crate::cpu::{branch_cycles, OP_CYCLES};
+use crate::symbols::Symbols;
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use std::fmt::{self, Display, Formatter};
+
+const R: [&str; 8] = ["B", "C", "D", "E", "H", "L", "(HL)", "A"];
+const RP: [&str; 4] = ["BC", "DE", "HL", "SP"];
+const RP2: [&str; 4] = ["BC", "DE", "HL", "AF"];
+const CC: [&str; 4] = ["NZ", "Z", "NC", "C"];
+const ALU: [(&str, &str); 8] = [
+    ("ADD", "A, "),
+    ("ADC", "A, "),
+    ("SUB", ""),
+    ("SBC", "A, "),
+    ("AND", ""),
+    ("XOR", ""),
+    ("OR", ""),
+    ("CP", ""),
+];
+const ROT: [&str; 8] = ["RLC", "RRC", "RL", "RR", "SLA", "SRA", "SWAP", "SRL"];
+const X0_Z7: [&str; 8] = ["RLCA", "RRCA", "RLA", "RRA", "DAA", "CPL", "SCF", "CCF"];
+
+/// A decoded instruction
+#[derive(Clone, Debug, PartialEq)]
+pub struct Instruction {
+    pub addr: u16,
+    pub bytes: Vec<u8>,
+    pub mnemonic: &'static str,
+    pub operands: String,
+    pub cycles: u8,                /* not taken for conditional branches */
+    pub branch_cycles: Option<u8>, /* when a conditional branch is taken */
+    pub target: Option<u16>,       /* absolute address the instruction uses */
+}
+
+impl Instruction {
+    /// Length in bytes, an instruction is never empty
+    #[allow(clippy::len_without_is_empty)]
+    pub fn len(&self) -> u16 {
+        self.bytes.len() as u16
+    }
+
+    /// The instruction with its target address replaced by a label,
+    /// `bank_of` gives the bank mapped at an address
+    pub fn labelled(&self, symbols: &Symbols, bank_of: impl Fn(u16) -> u16) -> String {
+        let text = self.to_string();
+        let label = self
+            .target
+            .and_then(|target| Some((target, symbols.label(bank_of(target), target)?)));
+        match label {
+            Some((target, label)) => text.replacen(&format!("${:04X}", target), label, 1),
+            None => text,
+        }
+    }
+
+    /// `ADDR: BYTES  INSTRUCTION` line of a listing
+    pub fn listing(&self, symbols: &Symbols, bank_of: impl Fn(u16) -> u16) -> String {
+        let bytes: Vec<String> = self
+            .bytes
+            .iter()
+            .map(|byte| format!("{:02X}", byte))
+            .collect();
+        format!(
+            "{:04X}: {:<8}  {}",
+            self.addr,
+            bytes.join(" "),
+            self.labelled(symbols, bank_of)
+        )
+    }
+}
+
+impl Display for Instruction {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if self.operands.is_empty() {
+            write!(f, "{}", self.mnemonic)
+        } else {
+            write!(f, "{} {}", self.mnemonic, self.operands)
+        }
+    }
+}
+
+/// Decodes the instruction at `addr`, reading bytes through `read`
+pub fn decode(addr: u16, read: impl Fn(u16) -> u8) -> Instruction {
+    let op = read(addr);
+    let d8 = read(addr.wrapping_add(1));
+    let d16 = u16::from_le_bytes([d8, read(addr.wrapping_add(2))]);
+    let rel = addr.wrapping_add(2).wrapping_add(d8 as i8 as u16);
+    let signed = if (d8 as i8) < 0 {
+        format!("-${:02X}", (d8 as i8).unsigned_abs())
+    } else {
+        format!("+${:02X}", d8)
+    };
+
+    let (x, y, z) = (op >> 6, ((op >> 3) & 7) as usize, (op & 7) as usize);
+    let (p, q) = (y >> 1, y & 1);
+    let mut length = 1;
+    let mut target = None;
+    let (mnemonic, operands): (&'static str, String) = match (x, z) {
+        (0, 0) => match y {
+            0 => ("NOP", String::new()),
+            1 => {
+                length = 3;
+                target = Some(d16);
+                ("LD", format!("(${:04X}), SP", d16))
+            }
+            2 => {
+                length = 2;
+                ("STOP", String::new())
+            }
+            3 => {
+                length = 2;
+                target = Some(rel);
+                ("JR", format!("${:04X}", rel))
+            }
+            _ => {
+                length = 2;
+                target = Some(rel);
+                ("JR", format!("{}, ${:04X}", CC[y - 4], rel))
+            }
+        },
+        (0, 1) if q == 0 => {
+            length = 3;
+            ("LD", format!("{}, ${:04X}", RP[p], d16))
+        }
+        (0, 1) => ("ADD", format!("HL, {}", RP[p])),
+        (0, 2) => {
+            let indirect = ["(BC)", "(DE)", "(HL+)", "(HL-)"][p];
+            if q == 0 {
+                ("LD", format!("{}, A", indirect))
+            } else {
+                ("LD", format!("A, {}", indirect))
+            }
+        }
+        (0, 3) => (["INC", "DEC"][q], RP[p].to_string()),
+        (0, 4) => ("INC", R[y].to_string()),
+        (0, 5) => ("DEC", R[y].to_string()),
+        (0, 6) => {
+            length = 2;
+            ("LD", format!("{}, ${:02X}", R[y], d8))
+        }
+        (0, _) => (X0_Z7[y], String::new()),
+        (1, 6) if y == 6 => ("HALT", String::new()),
+        (1, _) => ("LD", format!("{}, {}", R[y], R[z])),
+        (2, _) => (ALU[y].0, format!("{}{}", ALU[y].1, R[z])),
+        (3, 0) => match y {
+            0..=3 => ("RET", CC[y].to_string()),
+            4 | 6 => {
+                length = 2;
+                target = Some(0xFF00 | d8 as u16);
+                let indirect = format!("(${:04X})", 0xFF00 | d8 as u16);
+                if y == 4 {
+                    ("LDH", format!("{}, A", indirect))
+                } else {
+                    ("LDH", format!("A, {}", indirect))
+                }
+            }
+            5 => {
+                length = 2;
+                ("ADD", format!("SP, {}", signed))
+            }
+            _ => {
+                length = 2;
+                ("LD", format!("HL, SP{}", signed))
+            }
+        },
+        (3, 1) if q == 0 => ("POP", RP2[p].to_string()),
+        (3, 1) => match p {
+            0 => ("RET", String::new()),
+            1 => ("RETI", String::new()),
+            2 => ("JP", "HL".to_string()),
+            _ => ("LD", "SP, HL".to_string()),
+        },
+        (3, 2) => match y {
+            0..=3 => {
+                length = 3;
+                target = Some(d16);
+                ("JP", format!("{}, ${:04X}", CC[y], d16))
+            }
+            4 => ("LD", "(C), A".to_string()),
+            6 => ("LD", "A, (C)".to_string()),
+            _ => {
+                length = 3;
+                target = Some(d16);
+                if y == 5 {
+                    ("LD", format!("(${:04X}), A", d16))
+                } else {
+                    ("LD", format!("A, (${:04X})", d16))
+                }
+            }
+        },
+        (3, 3) if y == 0 => {
+            length = 3;
+            target = Some(d16);
+            ("JP", format!("${:04X}", d16))
+        }
+        (3, 3) if y == 1 => {
+            let cb = decode_cb(d8);
+            return Instruction {
+                addr,
+                bytes: vec![op, d8],
+                mnemonic: cb.0,
+                operands: cb.1,
+                cycles: cb.2,
+                branch_cycles: None,
+                target: None,
+            };
+        }
+        (3, 3) if y == 6 => ("DI", String::new()),
+        (3, 3) if y == 7 => ("EI", String::new()),
+        (3, 4) if y < 4 => {
+            length = 3;
+            target = Some(d16);
+            ("CALL", format!("{}, ${:04X}", CC[y], d16))
+        }
+        (3, 5) if q == 0 => ("PUSH", RP2[p].to_string()),
+        (3, 5) if p == 0 => {
+            length = 3;
+            target = Some(d16);
+            ("CALL", format!("${:04X}", d16))
+        }
+        (3, 6) => {
+            length = 2;
+            (ALU[y].0, format!("{}${:02X}", ALU[y].1, d8))
+        }
+        (3, 7) => {
+            target = Some(y as u16 * 8);
+            ("RST", format!("${:02X}", y * 8))
+        }
+        // The remaining opcodes lock the cpu up
+        _ => ("DB", format!("${:02X}", op)),
+    };
+
+    Instruction {
+        addr,
+        bytes: (0..length).map(|i| read(addr.wrapping_add(i))).collect(),
+        mnemonic,
+        operands,
+        cycles: OP_CYCLES[op as usize],
+        branch_cycles: branch_cycles(op),
+        target,
+    }
+}
+
+/// Mnemonic, operands and cycles of a 0xCB prefixed opcode
+fn decode_cb(op: u8) -> (&'static str, String, u8) {
+    let (x, y, z) = (op >> 6, (op >> 3) & 7, (op & 7) as usize);
+    // (HL) adds a memory read, and a write back for everything but BIT
+    let cycles = match (x, z) {
+        (1, 6) => 12,
+        (_, 6) => 16,
+        _ => 8,
+    };
+    match x {
+        0 => (ROT[y as usize], R[z].to_string(), cycles),
+        _ => (
+            ["BIT", "RES", "SET"][x as usize - 1],
+            format!("{}, {}", y, R[z]),
+            cycles,
+        ),
+    }
+}
+
+/// Lists the instructions from `start` to `end` inclusive in a rom bank.
+/// Bank 0 is mapped at 0x0000 and every other bank at 0x4000.
+pub fn rom_listing(rom: &[u8], bank: usize, start: u16, end: u16) -> Vec<Instruction> {
+    let base = if bank == 0 { 0 } else { 0x4000 };
+    let read = |addr: u16| {
+        let offset = bank * 0x4000 + (addr as usize).wrapping_sub(base);
+        if (base..base + 0x4000).contains(&(addr as usize)) {
+            rom.get(offset).copied().unwrap_or(0xFF)
+        } else {
+            0xFF
+        }
+    };
+    let mut listing = Vec::new();
+    let mut addr = start as u32;
+    while addr <= end as u32 {
+        let instruction = decode(addr as u16, read);
+        addr += instruction.len() as u32;
+        listing.push(instruction);
+    }
+    listing
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn disasm(bytes: &[u8]) -> Instruction {
+        let mut mem = bytes.to_vec();
+        mem.resize(4, 0);
+        decode(0x0150, |addr| mem[(addr - 0x0150) as usize])
+    }
+
+    #[test]
+    fn operands() {
+        assert!(disasm(&[0x00]).to_string() == "NOP");
+        assert!(disasm(&[0x01, 0x34, 0x12]).to_string() == "LD BC, $1234");
+        assert!(disasm(&[0x22]).to_string() == "LD (HL+), A");
+        assert!(disasm(&[0x46]).to_string() == "LD B, (HL)");
+        assert!(disasm(&[0x76]).to_string() == "HALT");
+        assert!(disasm(&[0x9F]).to_string() == "SBC A, A");
+        assert!(disasm(&[0xE0, 0x44]).to_string() == "LDH ($FF44), A");
+        assert!(disasm(&[0xF8, 0xFE]).to_string() == "LD HL, SP-$02");
+        assert!(disasm(&[0xFE, 0x90]).to_string() == "CP $90");
+        assert!(disasm(&[0xFF]).to_string() == "RST $38");
+        assert!(disasm(&[0xD3]).to_string() == "DB $D3");
+    }
+
+    #[test]
+    fn branches() {
+        let jr = disasm(&[0x20, 0xFE]);
+        assert!(jr.to_string() == "JR NZ, $0150");
+        assert!(jr.cycles == 8 && jr.branch_cycles == Some(12));
+        assert!(jr.target == Some(0x0150) && jr.len() == 2);
+        let call = disasm(&[0xCD, 0x00, 0x40]);
+        assert!(call.to_string() == "CALL $4000");
+        assert!(call.cycles == 24 && call.branch_cycles.is_none());
+        assert!(disasm(&[0xC8]).branch_cycles == Some(20));
+    }
+
+    #[test]
+    fn prefixed() {
+        let bit = disasm(&[0xCB, 0x7E]);
+        assert!(bit.to_string() == "BIT 7, (HL)");
+        assert!(bit.cycles == 12 && bit.len() == 2);
+        assert!(disasm(&[0xCB, 0x37]).to_string() == "SWAP A");
+        assert!(disasm(&[0xCB, 0xC6]).cycles == 16);
+    }
+
+    #[test]
+    fn labels() {
+        let symbols = Symbols::parse("00:0150 Main\n01:4000 Far\n");
+        let jr = disasm(&[0x18, 0xFE]);
+        assert!(jr.labelled(&symbols, |_| 0) == "JR Main");
+        let call = disasm(&[0xCD, 0x00, 0x40]);
+        assert!(call.labelled(&symbols, |_| 1) == "CALL Far");
+        assert!(call.labelled(&symbols, |_| 2) == "CALL $4000");
+        assert!(call.listing(&symbols, |_| 1) == "0150: CD 00 40  CALL Far");
+    }
+
+    #[test]
+    fn banked_listing() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x4000] = 0xC3;
+        rom[0x4001] = 0x00;
+        rom[0x4002] = 0x40;
+        let listing = rom_listing(&rom, 1, 0x4000, 0x4003);
+        assert!(listing.len() == 2);
+        assert!(listing[0].to_string() == "JP $4000");
+        assert!(listing[1].addr == 0x4003);
+    }
+}
@@ -0,0 +1,279 @@
+use crate::cpu::{Cpu, Lockup, Reg};
+use crate::mem::{CartHeader, Memory, Model};
+use crate::patch;
+use crate::romfile;
+use crate::state;
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+/// A whole Gameboy, the entry point for embedding the emulator
+pub struct GameBoy {
+    cpu: Cpu,
+    cartridge: CartHeader,
+    model: Model,
+}
+
+impl Default for GameBoy {
+    fn default() -> Self {
+        Self::new(Model::Auto)
+    }
+}
+
+impl GameBoy {
+    /// A machine with an empty cartridge, load a rom before running it
+    pub fn new(model: Model) -> Self {
+        let mut memory = Memory::default();
+        memory.set_model(model);
+        let cartridge = memory
+            .load_rom_bytes(vec![0; 0x8000])
+            .expect("An empty rom only has a rom only cartridge header");
+        Self {
+            cpu: Cpu::new(memory),
+            cartridge,
+            model,
+        }
+    }
+
+    /// Inserts the rom and powers the machine on from scratch. The rom can
+    /// also be gzip compressed or a zip archive with a .gb or .gbc file.
+    pub fn load_rom(&mut self, rom: &[u8]) -> io::Result<&CartHeader> {
+        self.insert(romfile::decode(rom, None)?.into_owned())
+    }
+
+    /// Like `load_rom`, also applying the `<rom>.ips`, `.ups` and `.bps`
+    /// patches and loading the labels of `<rom>.sym` next to it. `entry`
+    /// picks the file of a zip archive.
+    pub fn load_rom_file(&mut self, path: &Path, entry: Option<&str>) -> io::Result<&CartHeader> {
+        self.load_patched_rom_file(path, entry, &patch::find(path))
+    }
+
+    /// Like `load_rom_file`, applying the given patches in order instead of
+    /// the ones next to the rom. The original file is left untouched.
+    pub fn load_patched_rom_file(
+        &mut self,
+        path: &Path,
+        entry: Option<&str>,
+        patches: &[PathBuf],
+    ) -> io::Result<&CartHeader> {
+        let rom = romfile::read(path, entry)?;
+        self.insert(patch::apply_files(rom, patches)?)?;
+        self.cpu.mem_mut().load_rom_symbols(path);
+        Ok(&self.cartridge)
+    }
+
+    /// Powers on with the rom, a rom with a broken header is refused and
+    /// the previous one stays inserted
+    fn insert(&mut self, rom: Vec<u8>) -> io::Result<&CartHeader> {
+        let mut memory = Memory::default();
+        memory.set_model(self.model);
+        self.cartridge = memory.load_rom_bytes(rom)?;
+        self.cpu = Cpu::new(memory);
+        self.cpu.initialize(&self.cartridge);
+        Ok(&self.cartridge)
+    }
+
+    pub fn cartridge(&self) -> &CartHeader {
+        &self.cartridge
+    }
+
+    /// Runs until the ppu completed the next frame
+    pub fn run_frame(&mut self) {
+        self.cpu.run_frames(1);
+    }
+
+    /// Executes a single instruction
+    pub fn step(&mut self) {
+        self.cpu.step();
+    }
+
+    /// RGB555 colours of the last frame, `SCREEN_WIDTH` pixels per row
+    pub fn framebuffer(&self) -> &[u16] {
+        self.cpu.mem().framebuffer()
+    }
+
+    /// Interleaved stereo samples produced since the last call.
+    ///
+    /// This is a placeholder, there is no APU yet and it always returns an
+    /// empty `Vec`. An empty result does not mean the game is silent.
+    pub fn audio_samples(&mut self) -> Vec<i16> {
+        Vec::new()
+    }
+
+    /// Sets the pressed buttons, a combination of the bits in `joypad`
+    pub fn set_input(&mut self, buttons: u8) {
+        self.cpu.mem_mut().set_input(buttons);
+    }
+
+    /// Number of frames completed since power on
+    pub fn frame(&self) -> u64 {
+        self.cpu.mem().frame()
+    }
+
+    /// Number of cpu cycles since power on
+    pub fn cycle(&self) -> u64 {
+        self.cpu.cycle()
+    }
+
+    pub fn reg(&self, reg: Reg) -> u16 {
+        self.cpu.reg(reg)
+    }
+
+    pub fn set_reg(&mut self, reg: Reg, val: u16) {
+        self.cpu.set_reg(reg, val);
+    }
+
+    /// Reads memory without side effects
+    pub fn read(&self, addr: u16) -> u8 {
+        self.cpu.mem().peek8(addr)
+    }
+
+    pub fn write(&mut self, addr: u16, val: u8) {
+        self.cpu.mem_mut().write(addr, val);
+    }
+
+    /// The illegal opcode the cpu hung on, if it did
+    pub fn lockup(&self) -> Option<Lockup> {
+        self.cpu.lockup()
+    }
+
+    /// Serializes the whole machine, only loadable with the same rom
+    pub fn save_state(&self) -> Vec<u8> {
+        state::save_bytes(&self.cpu, &self.cartridge)
+    }
+
+    pub fn load_state(&mut self, data: &[u8]) -> io::Result<()> {
+        state::load_bytes(&mut self.cpu, &self.cartridge, data)
+    }
+
+    /// The cpu, with the rest of the system behind `Cpu::mem`, for tools
+    /// that need the internals
+    pub fn cpu(&self) -> &Cpu {
+        &self.cpu
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut Cpu {
+        &mut self.cpu
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::joypad;
+    use crate::video::{SCREEN_HEIGHT, SCREEN_WIDTH};
+    use std::io::ErrorKind;
+
+    fn rom() -> Vec<u8> {
+        // JP $0100 at the entry point
+        let mut rom = vec![0; 0x8000];
+        rom[0x134..0x138].copy_from_slice(b"LOOP");
+        rom[0x100..0x103].copy_from_slice(&[0xC3, 0x00, 0x01]);
+        rom
+    }
+
+    #[test]
+    fn runs_frames() {
+        let mut gameboy = GameBoy::default();
+        assert!(gameboy.load_rom(&rom()).unwrap().title.starts_with("LOOP"));
+        assert!(gameboy.reg(Reg::PC) == 0x0100);
+        gameboy.run_frame();
+        gameboy.run_frame();
+        assert!(gameboy.frame() == 2);
+        assert!(gameboy.reg(Reg::PC) == 0x0100);
+        assert!(gameboy.framebuffer().len() == SCREEN_WIDTH * SCREEN_HEIGHT);
+        assert!(gameboy.audio_samples().is_empty());
+        assert!(gameboy.lockup().is_none());
+    }
+
+    #[test]
+    fn compressed_rom() {
+        use flate2::{write::GzEncoder, Compression};
+        use std::io::Write;
+
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&rom()).unwrap();
+        let mut gameboy = GameBoy::default();
+        let compressed = encoder.finish().unwrap();
+        assert!(gameboy
+            .load_rom(&compressed)
+            .unwrap()
+            .title
+            .starts_with("LOOP"));
+        assert!(gameboy.read(0x0100) == 0xC3);
+    }
+
+    #[test]
+    fn archived_rom_with_broken_header() {
+        use std::io::{Cursor, Write};
+        use zip::{write::FileOptions, ZipWriter};
+
+        let mut rom = rom();
+        rom[0x147] = 0x42;
+        let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+        writer
+            .start_file("game.gb", FileOptions::default())
+            .unwrap();
+        writer.write_all(&rom).unwrap();
+        let data = writer.finish().unwrap().into_inner();
+        let mut gameboy = GameBoy::default();
+        assert!(gameboy.load_rom(&data).is_err());
+    }
+
+    #[test]
+    fn short_rom_is_refused() {
+        let mut gameboy = GameBoy::new(Model::Dmg);
+        assert!(gameboy.load_rom(&[0; 0x100]).is_err());
+    }
+
+    #[test]
+    fn short_rom_reads_open_bus() {
+        // Only the header, the JP $0100 loop never leaves it
+        let mut rom = rom();
+        rom.truncate(0x150);
+        let mut gameboy = GameBoy::default();
+        gameboy.load_rom(&rom).unwrap();
+        gameboy.run_frame();
+        assert!(gameboy.reg(Reg::PC) == 0x0100);
+        assert!(gameboy.read(0x0150) == 0xFF);
+        assert!(gameboy.read(0x7FFF) == 0xFF);
+    }
+
+    #[test]
+    fn broken_headers_are_refused() {
+        let mut gameboy = GameBoy::default();
+        gameboy.load_rom(&rom()).unwrap();
+        let mut unknown_type = rom();
+        unknown_type[0x147] = 0x42;
+        let mut unknown_size = rom();
+        unknown_size[0x148] = 0x52;
+        for rom in &[unknown_type, unknown_size] {
+            let e = gameboy.load_rom(rom).err().unwrap();
+            assert!(e.kind() == ErrorKind::InvalidData);
+        }
+        // The working rom stays inserted
+        assert!(gameboy.cartridge().title.starts_with("LOOP"));
+        assert!(gameboy.read(0x0100) == 0xC3);
+    }
+
+    #[test]
+    fn state_and_input() {
+        let mut gameboy = GameBoy::default();
+        gameboy.load_rom(&rom()).unwrap();
+        gameboy.write(0xC000, 0x42);
+        gameboy.run_frame();
+        let saved = gameboy.save_state();
+        gameboy.write(0xC000, 0x00);
+        gameboy.run_frame();
+        gameboy.load_state(&saved).unwrap();
+        assert!(gameboy.read(0xC000) == 0x42);
+        assert!(gameboy.frame() == 1);
+
+        gameboy.write(0xFF00, 0x10); // select the buttons
+        gameboy.set_input(joypad::START);
+        assert!(gameboy.read(0xFF00) & 0x0F == 0x07);
+    }
+}
@@ -0,0 +1,491 @@
+use crate::cpu::{Cpu, Reg};
+use crate::watch::{Access, Watchpoint};
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use std::{
+    io::{self, Error, ErrorKind, Read, Write},
+    net::{TcpListener, TcpStream},
+    panic::{self, AssertUnwindSafe},
+};
+
+/* Registers in the order of `g` packets, 16 bits little endian each */
+const REGISTERS: [Reg; 6] = [Reg::AF, Reg::BC, Reg::DE, Reg::HL, Reg::SP, Reg::PC];
+/* Instructions run between checks for an interrupt from the client */
+const INTERRUPT_CHECK: u32 = 0x1000;
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+<architecture>z80</architecture>
+<feature name="org.gnu.gdb.z80.cpu">
+<reg name="af" bitsize="16" type="int"/>
+<reg name="bc" bitsize="16" type="int"/>
+<reg name="de" bitsize="16" type="int"/>
+<reg name="hl" bitsize="16" type="int"/>
+<reg name="sp" bitsize="16" type="data_ptr"/>
+<reg name="pc" bitsize="16" type="code_ptr"/>
+</feature>
+</target>"#;
+
+/// What the session does after handling a packet
+#[derive(Debug, PartialEq)]
+enum Action {
+    Reply(String),
+    Resume { step: bool },
+    Detach,
+    Kill,
+}
+
+/// Why execution stopped, reported to the client as a stop reply
+enum Stop {
+    Trap,
+    Watch { kind: &'static str, addr: u16 },
+    Interrupt,
+    Crash,
+    Lockup,
+}
+
+impl Stop {
+    fn reply(&self) -> String {
+        match self {
+            Stop::Trap => "S05".to_string(),
+            Stop::Watch { kind, addr } => format!("T05{}:{:04x};", kind, addr),
+            Stop::Interrupt => "S02".to_string(),
+            // SIGABRT, the emulator gave up on something it does not support
+            Stop::Crash => "S06".to_string(),
+            // SIGILL, like an illegal instruction on other targets
+            Stop::Lockup => "S04".to_string(),
+        }
+    }
+}
+
+/// GDB remote serial protocol server, keeps the software breakpoints
+/// while watchpoints are forwarded to `Memory`
+#[derive(Default)]
+pub struct GdbStub {
+    breakpoints: Vec<u16>,
+}
+
+impl GdbStub {
+    /// Waits for a client on the local port and serves it until it
+    /// detaches or kills the session
+    pub fn listen(&mut self, cpu: &mut Cpu, port: u16) -> io::Result<()> {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        println!("Waiting for a GDB connection on 127.0.0.1:{}", port);
+        let (stream, addr) = listener.accept()?;
+        info!("GDB client connected from {}", addr);
+        stream.set_nodelay(true)?;
+        self.serve(cpu, stream)
+    }
+
+    fn serve(&mut self, cpu: &mut Cpu, mut stream: TcpStream) -> io::Result<()> {
+        while let Some(packet) = read_packet(&mut stream)? {
+            debug!("GDB packet: {}", packet);
+            match self.handle(cpu, &packet) {
+                Action::Reply(reply) => write_packet(&mut stream, &reply)?,
+                Action::Resume { step } => {
+                    let stop = self.resume(cpu, step, &mut stream)?;
+                    // The client reads registers of every component next
+                    cpu.mem_mut().sync_all();
+                    write_packet(&mut stream, &stop.reply())?;
+                }
+                Action::Detach => {
+                    write_packet(&mut stream, "OK")?;
+                    break;
+                }
+                Action::Kill => break,
+            }
+        }
+        info!("GDB session ended");
+        Ok(())
+    }
+
+    fn handle(&mut self, cpu: &mut Cpu, packet: &str) -> Action {
+        let reply = |reply: &str| Action::Reply(reply.to_string());
+        let (command, args) = packet.split_at(packet.len().min(1));
+        match command {
+            "?" => reply("S05"),
+            "g" => Action::Reply(
+                REGISTERS
+                    .iter()
+                    .map(|reg| hex(&cpu.reg(*reg).to_le_bytes()))
+                    .collect(),
+            ),
+            "G" => match parse_bytes(args) {
+                Some(bytes) if bytes.len() >= REGISTERS.len() * 2 => {
+                    for (reg, val) in REGISTERS.iter().zip(bytes.chunks(2)) {
+                        cpu.set_reg(*reg, u16::from_le_bytes([val[0], val[1]]));
+                    }
+                    reply("OK")
+                }
+                _ => reply("E01"),
+            },
+            "p" => match parse_hex(args).and_then(|index| REGISTERS.get(index as usize)) {
+                Some(reg) => Action::Reply(hex(&cpu.reg(*reg).to_le_bytes())),
+                None => reply("E01"),
+            },
+            "P" => {
+                let register = args.split_once('=').and_then(|(index, val)| {
+                    let reg = REGISTERS.get(parse_hex(index)? as usize)?;
+                    let val = parse_bytes(val).filter(|val| val.len() == 2)?;
+                    Some((*reg, u16::from_le_bytes([val[0], val[1]])))
+                });
+                match register {
+                    Some((reg, val)) => {
+                        cpu.set_reg(reg, val);
+                        reply("OK")
+                    }
+                    None => reply("E01"),
+                }
+            }
+            "m" => match parse_range(args) {
+                Some((addr, len)) => Action::Reply(hex(&(0..len)
+                    .map(|offset| cpu.mem().peek8(addr.wrapping_add(offset)))
+                    .collect::<Vec<u8>>())),
+                None => reply("E01"),
+            },
+            "M" => {
+                let write = args.split_once(':').and_then(|(range, data)| {
+                    let (addr, len) = parse_range(range)?;
+                    parse_bytes(data)
+                        .filter(|data| data.len() == len as usize)
+                        .map(|data| (addr, data))
+                });
+                match write {
+                    Some((addr, data)) => {
+                        for (offset, val) in data.iter().enumerate() {
+                            cpu.mem_mut().poke8(addr.wrapping_add(offset as u16), *val);
+                        }
+                        reply("OK")
+                    }
+                    None => reply("E01"),
+                }
+            }
+            "c" | "s" => {
+                if let Some(addr) = parse_hex(args) {
+                    cpu.set_reg(Reg::PC, addr);
+                }
+                Action::Resume {
+                    step: command == "s",
+                }
+            }
+            "Z" | "z" => self.breakpoint(cpu, command == "Z", args),
+            "H" => reply("OK"),
+            "D" => Action::Detach,
+            "k" => Action::Kill,
+            "q" => self.query(args),
+            _ => reply(""),
+        }
+    }
+
+    fn query(&self, query: &str) -> Action {
+        let reply = |reply: &str| Action::Reply(reply.to_string());
+        if query.starts_with("Supported") {
+            return reply("PacketSize=4000;qXfer:features:read+");
+        }
+        if let Some(range) = query.strip_prefix("Xfer:features:read:target.xml:") {
+            // Reads come in chunks, `l` marks the last one
+            return match parse_range(range) {
+                Some((offset, len)) => {
+                    let offset = (offset as usize).min(TARGET_XML.len());
+                    let end = (offset + len as usize).min(TARGET_XML.len());
+                    let marker = if end == TARGET_XML.len() { 'l' } else { 'm' };
+                    Action::Reply(format!("{}{}", marker, &TARGET_XML[offset..end]))
+                }
+                None => reply("E01"),
+            };
+        }
+        match query {
+            "Attached" => reply("1"),
+            "C" => reply("QC1"),
+            "fThreadInfo" => reply("m1"),
+            "sThreadInfo" => reply("l"),
+            _ => reply(""),
+        }
+    }
+
+    /// Handles `Z`/`z` packets, type 0 and 1 are breakpoints, 2 to 4 are
+    /// write, read and access watchpoints
+    fn breakpoint(&mut self, cpu: &mut Cpu, insert: bool, args: &str) -> Action {
+        let mut fields = args.split(',');
+        let kind = fields.next();
+        let addr = fields.next().and_then(parse_hex);
+        let len = fields.next().and_then(parse_hex).unwrap_or(1).max(1);
+        let addr = match addr {
+            Some(addr) => addr,
+            None => return Action::Reply("E01".to_string()),
+        };
+        let access = match kind {
+            Some("0") | Some("1") => {
+                if insert && !self.breakpoints.contains(&addr) {
+                    self.breakpoints.push(addr);
+                } else if !insert {
+                    self.breakpoints.retain(|breakpoint| *breakpoint != addr);
+                }
+                return Action::Reply("OK".to_string());
+            }
+            Some("2") => Access::Write,
+            Some("3") => Access::Read,
+            Some("4") => Access::ReadWrite,
+            _ => return Action::Reply(String::new()),
+        };
+        let watchpoint = Watchpoint {
+            start: addr,
+            end: addr.saturating_add(len - 1),
+            access,
+            value: None,
+        };
+        if insert {
+            cpu.mem_mut().add_watchpoint(watchpoint);
+        } else if let Some(index) = cpu
+            .mem()
+            .watchpoints()
+            .iter()
+            .position(|watch| *watch == watchpoint)
+        {
+            cpu.mem_mut().remove_watchpoint(index);
+        }
+        Action::Reply("OK".to_string())
+    }
+
+    /// Runs until a breakpoint, a watchpoint, a crash or an interrupt from
+    /// the client, or a single instruction when stepping
+    fn resume(&mut self, cpu: &mut Cpu, step: bool, stream: &mut TcpStream) -> io::Result<Stop> {
+        let mut count: u32 = 0;
+        loop {
+            if panic::catch_unwind(AssertUnwindSafe(|| cpu.step())).is_err() {
+                return Ok(Stop::Crash);
+            }
+            if cpu.lockup().is_some() {
+                return Ok(Stop::Lockup);
+            }
+            if let Some(hit) = cpu.mem_mut().take_watch_hit() {
+                let watchpoint = cpu.mem().watchpoints()[hit.index];
+                let kind = match watchpoint.access {
+                    Access::Write => "watch",
+                    Access::Read => "rwatch",
+                    Access::ReadWrite => "awatch",
+                };
+                return Ok(Stop::Watch {
+                    kind,
+                    addr: hit.addr,
+                });
+            }
+            if step || self.breakpoints.contains(&cpu.reg(Reg::PC)) {
+                return Ok(Stop::Trap);
+            }
+            count = count.wrapping_add(1);
+            if count % INTERRUPT_CHECK == 0 && interrupted(stream)? {
+                return Ok(Stop::Interrupt);
+            }
+        }
+    }
+}
+
+/// Checks for the 0x03 byte a client sends to interrupt execution
+fn interrupted(stream: &mut TcpStream) -> io::Result<bool> {
+    stream.set_nonblocking(true)?;
+    let mut byte = [0];
+    let result = stream.read(&mut byte);
+    stream.set_nonblocking(false)?;
+    match result {
+        Ok(0) => Err(Error::new(
+            ErrorKind::UnexpectedEof,
+            "GDB client disconnected",
+        )),
+        Ok(_) => Ok(byte[0] == 0x03),
+        Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+fn checksum(data: &str) -> u8 {
+    data.bytes().fold(0, |sum, byte| sum.wrapping_add(byte))
+}
+
+/// Reads the next `$data#xx` packet and acknowledges it, returns None
+/// once the client disconnects
+fn read_packet(stream: &mut impl ReadWrite) -> io::Result<Option<String>> {
+    let mut byte = [0];
+    loop {
+        // Acks and interrupts outside of a packet are skipped
+        loop {
+            if stream.read(&mut byte)? == 0 {
+                return Ok(None);
+            }
+            if byte[0] == b'$' {
+                break;
+            }
+        }
+        let mut data = Vec::new();
+        loop {
+            if stream.read(&mut byte)? == 0 {
+                return Ok(None);
+            }
+            if byte[0] == b'#' {
+                break;
+            }
+            data.push(byte[0]);
+        }
+        let mut sum = [0; 2];
+        stream.read_exact(&mut sum)?;
+        let data = String::from_utf8_lossy(&data).into_owned();
+        let expected = std::str::from_utf8(&sum)
+            .ok()
+            .and_then(|sum| u8::from_str_radix(sum, 16).ok());
+        if expected == Some(checksum(&data)) {
+            stream.write_all(b"+")?;
+            return Ok(Some(data));
+        }
+        warn!("Dropping GDB packet with a bad checksum: {}", data);
+        stream.write_all(b"-")?;
+    }
+}
+
+fn write_packet(stream: &mut impl Write, data: &str) -> io::Result<()> {
+    debug!("GDB reply: {}", data);
+    write!(stream, "${}#{:02x}", data, checksum(data))?;
+    stream.flush()
+}
+
+trait ReadWrite: Read + Write {}
+impl<T: Read + Write> ReadWrite for T {}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn parse_hex(val: &str) -> Option<u16> {
+    u16::from_str_radix(val, 16).ok()
+}
+
+/// Parses `addr,len`
+fn parse_range(range: &str) -> Option<(u16, u16)> {
+    let (addr, len) = range.split_once(',')?;
+    Some((parse_hex(addr)?, parse_hex(len)?))
+}
+
+fn parse_bytes(data: &str) -> Option<Vec<u8>> {
+    if data.len() % 2 != 0 {
+        return None;
+    }
+    (0..data.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(data.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem::Memory;
+    use std::io::Cursor;
+
+    fn reply(stub: &mut GdbStub, cpu: &mut Cpu, packet: &str) -> String {
+        match stub.handle(cpu, packet) {
+            Action::Reply(reply) => reply,
+            action => panic!("Expected a reply, got {:?}", action),
+        }
+    }
+
+    /// Client side of a connection, replays the given input
+    struct Client {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for Client {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Client {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn packet_framing() {
+        let input = format!("+$m0,1#00$mc000,2#{:02x}\x03$g#67", checksum("mc000,2"));
+        let mut client = Client {
+            input: Cursor::new(input.into_bytes()),
+            output: Vec::new(),
+        };
+        // The bad checksum gets a nack, the next packet is read instead
+        assert!(read_packet(&mut client).unwrap().as_deref() == Some("mc000,2"));
+        assert!(read_packet(&mut client).unwrap().as_deref() == Some("g"));
+        assert!(read_packet(&mut client).unwrap().is_none());
+        assert!(client.output == b"-++");
+        let mut out = Vec::new();
+        write_packet(&mut out, "OK").unwrap();
+        assert!(out == b"$OK#9a");
+    }
+
+    #[test]
+    fn registers() {
+        let mut cpu = Cpu::new(Memory::default());
+        let mut stub = GdbStub::default();
+        cpu.set_reg(Reg::AF, 0x01B0);
+        cpu.set_reg(Reg::PC, 0x0150);
+        assert!(reply(&mut stub, &mut cpu, "g") == "b00100000000000000005001");
+        assert!(reply(&mut stub, &mut cpu, "P3=3412") == "OK");
+        assert!(cpu.reg(Reg::HL) == 0x1234);
+        assert!(reply(&mut stub, &mut cpu, "p5") == "5001");
+        assert!(reply(&mut stub, &mut cpu, "p6") == "E01");
+    }
+
+    #[test]
+    fn memory() {
+        let mut cpu = Cpu::new(Memory::default());
+        let mut stub = GdbStub::default();
+        assert!(reply(&mut stub, &mut cpu, "Mc000,3:0102ff") == "OK");
+        assert!(reply(&mut stub, &mut cpu, "mc001,2") == "02ff");
+        assert!(reply(&mut stub, &mut cpu, "Mc000,2:01") == "E01");
+        // Memory written by the client doesn't stop the next resume
+        assert!(reply(&mut stub, &mut cpu, "Z2,c000,1") == "OK");
+        assert!(reply(&mut stub, &mut cpu, "Mc000,1:42") == "OK");
+        assert!(cpu.mem_mut().take_watch_hit().is_none());
+    }
+
+    #[test]
+    fn breakpoints_and_watchpoints() {
+        let mut cpu = Cpu::new(Memory::default());
+        let mut stub = GdbStub::default();
+        assert!(reply(&mut stub, &mut cpu, "Z0,10,1") == "OK");
+        assert!(stub.breakpoints == vec![0x10]);
+        assert!(reply(&mut stub, &mut cpu, "z0,10,1") == "OK");
+        assert!(stub.breakpoints.is_empty());
+        assert!(reply(&mut stub, &mut cpu, "Z2,c0a0,2") == "OK");
+        assert!(cpu.mem().watchpoints()[0].end == 0xC0A1);
+        assert!(reply(&mut stub, &mut cpu, "z2,c0a0,2") == "OK");
+        assert!(cpu.mem().watchpoints().is_empty());
+        assert!(stub.handle(&mut cpu, "s") == Action::Resume { step: true });
+        assert!(stub.handle(&mut cpu, "c100") == Action::Resume { step: false });
+        assert!(cpu.reg(Reg::PC) == 0x100);
+    }
+
+    #[test]
+    fn crash_and_lockup_are_told_apart() {
+        assert!(Stop::Crash.reply() == "S06");
+        assert!(Stop::Lockup.reply() == "S04");
+    }
+
+    #[test]
+    fn target_description() {
+        let mut cpu = Cpu::new(Memory::default());
+        let mut stub = GdbStub::default();
+        let first = reply(&mut stub, &mut cpu, "qXfer:features:read:target.xml:0,10");
+        assert!(first == format!("m{}", &TARGET_XML[..0x10]));
+        let rest = reply(
+            &mut stub,
+            &mut cpu,
+            "qXfer:features:read:target.xml:10,1000",
+        );
+        assert!(rest == format!("l{}", &TARGET_XML[0x10..]));
+    }
+}
@@ -0,0 +1,103 @@
+use crate::state::{Savestate, StateReader, StateWriter};
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use std::io;
+
+/* Bit of the IF register requested by the joypad */
+pub const INT_JOYPAD: u8 = 1 << 4;
+
+/* Bits of the pressed buttons, directions are the low nibble */
+pub const RIGHT: u8 = 1 << 0;
+pub const LEFT: u8 = 1 << 1;
+pub const UP: u8 = 1 << 2;
+pub const DOWN: u8 = 1 << 3;
+pub const A: u8 = 1 << 4;
+pub const B: u8 = 1 << 5;
+pub const SELECT: u8 = 1 << 6;
+pub const START: u8 = 1 << 7;
+
+pub const BUTTON_NAMES: [(&str, u8); 8] = [
+    ("right", RIGHT),
+    ("left", LEFT),
+    ("up", UP),
+    ("down", DOWN),
+    ("a", A),
+    ("b", B),
+    ("select", SELECT),
+    ("start", START),
+];
+
+/// P1 register at 0xFF00
+#[derive(Default)]
+pub struct Joypad {
+    select: u8,  /* bits 4-5, a group is selected when its bit is 0 */
+    buttons: u8, /* pressed buttons */
+}
+
+impl Joypad {
+    pub fn read(&self) -> u8 {
+        let mut pressed = 0;
+        if self.select & (1 << 4) == 0 {
+            pressed |= self.buttons & 0x0F;
+        }
+        if self.select & (1 << 5) == 0 {
+            pressed |= self.buttons >> 4;
+        }
+        0xC0 | self.select | (!pressed & 0x0F)
+    }
+
+    pub fn write(&mut self, val: u8) {
+        self.select = val & 0x30;
+    }
+
+    /// Updates the pressed buttons, returns the interrupt of a new press
+    pub fn set_buttons(&mut self, buttons: u8) -> u8 {
+        let before = self.read();
+        self.buttons = buttons;
+        // Interrupt is requested when a selected line goes from high to low
+        if before & !self.read() & 0x0F != 0 {
+            INT_JOYPAD
+        } else {
+            0
+        }
+    }
+}
+
+impl Savestate for Joypad {
+    fn save_state(&self, out: &mut StateWriter) {
+        out.u8(self.select);
+        out.u8(self.buttons);
+    }
+
+    fn load_state(&mut self, input: &mut StateReader) -> io::Result<()> {
+        self.select = input.u8()? & 0x30;
+        self.buttons = input.u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn selected_group_is_read() {
+        let mut joypad = Joypad::default();
+        joypad.set_buttons(START | LEFT);
+        joypad.write(0x20); // directions
+        assert!(joypad.read() == 0xED);
+        joypad.write(0x10); // actions
+        assert!(joypad.read() == 0xD7);
+        joypad.write(0x30);
+        assert!(joypad.read() == 0xFF);
+    }
+
+    #[test]
+    fn press_requests_interrupt() {
+        let mut joypad = Joypad::default();
+        joypad.write(0x10);
+        assert!(joypad.set_buttons(UP) == 0);
+        assert!(joypad.set_buttons(UP | A) == INT_JOYPAD);
+        assert!(joypad.set_buttons(A) == 0);
+    }
+}
@@ -0,0 +1,25 @@
+//! Gameboy and Gameboy Color emulator. `GameBoy` runs a rom, the modules
+//! behind it are public for tools like the debugger that need the internals.
+
+pub mod cpu;
+pub mod debugger;
+pub mod disasm;
+mod gameboy;
+pub mod gdb;
+pub mod joypad;
+pub mod mem;
+pub mod movie;
+pub mod patch;
+pub mod rewind;
+pub mod romfile;
+mod scheduler;
+pub mod screenshot;
+pub mod state;
+pub mod symbols;
+mod timer;
+pub mod tracediff;
+pub mod tracelog;
+pub mod video;
+pub mod watch;
+
+pub use gameboy::GameBoy;
@@ -1,36 +1,258 @@
-use clap::{crate_authors, crate_version, App, Arg};
+use clap::{
+    crate_authors, crate_version, App, AppSettings, Arg, ArgGroup, ArgMatches, SubCommand,
+};
 #[allow(unused_imports)]
 use log::{debug, error, info, trace, warn};
 use simplelog::{LevelFilter, WriteLogger};
-use std::{env, io::stdout};
+use std::{
+    env,
+    fs::File,
+    io::{self, stdout, BufWriter},
+    path::{Path, PathBuf},
+    process,
+};
 
-mod cpu;
-use cpu::Cpu;
-mod mem;
-use mem::Memory;
+use uboy::{
+    debugger::Debugger,
+    disasm,
+    gdb::GdbStub,
+    mem::Model,
+    movie::{InputScript, Movie, HASH_INTERVAL},
+    patch,
+    rewind::Rewind,
+    romfile,
+    screenshot, state,
+    symbols::Symbols,
+    tracediff, tracelog, GameBoy,
+};
+
+/* Exit status when the cpu hung on an illegal opcode, 1 is taken by errors */
+const LOCKUP_EXIT_STATUS: i32 = 3;
+
+/// Options setting up the machine, shared by every subcommand that emulates
+fn machine_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("log")
+            .short("l")
+            .long("log-level")
+            .value_name("LEVEL")
+            .possible_values(&["off", "error", "warn", "debug", "info", "trace", "all"])
+            .default_value("all")
+            .number_of_values(1)
+            .help("The verbosity level of logs"),
+        Arg::with_name("model")
+            .short("m")
+            .long("model")
+            .value_name("MODEL")
+            .possible_values(&["auto", "dmg", "cgb"])
+            .default_value("auto")
+            .help("The hardware to emulate, cgb runs DMG games in compatibility mode"),
+        symbols_arg(),
+        entry_arg(),
+        patch_arg(),
+        Arg::with_name("trace")
+            .long("trace")
+            .value_name("FILE")
+            .help("Writes the cpu state before every instruction in gameboy-doctor format"),
+        Arg::with_name("trace-log")
+            .long("trace-log")
+            .help("Logs every instruction and memory access at trace level, needs the trace-log feature"),
+        Arg::with_name("stub-ly")
+            .long("stub-ly")
+            .help("Makes LY always read 0x90, as gameboy-doctor logs expect"),
+        Arg::with_name("rom")
+            .help("Set the rom file to use")
+            .required(true)
+            .index(1),
+    ]
+}
+
+/// Options shared by running a rom directly and the `run` subcommand
+fn emulation_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    let mut args = machine_args();
+    args.extend(vec![
+        Arg::with_name("slot")
+            .short("s")
+            .long("slot")
+            .value_name("SLOT")
+            .possible_values(&["0", "1", "2", "3", "4", "5", "6", "7", "8", "9"])
+            .default_value("0")
+            .help("The save state slot used by --load-state and --save-state"),
+        Arg::with_name("load-state")
+            .long("load-state")
+            .help("Loads the save state slot before running"),
+        Arg::with_name("save-state")
+            .long("save-state")
+            .requires("duration")
+            .help("Saves to the save state slot when the emulation stops"),
+        Arg::with_name("frames")
+            .short("f")
+            .long("frames")
+            .value_name("N")
+            .help("Stops the emulation after N frames"),
+        Arg::with_name("cycles")
+            .short("c")
+            .long("cycles")
+            .value_name("N")
+            .conflicts_with_all(&["rewind", "input", "record-movie"])
+            .help("Stops the emulation after N cpu cycles"),
+        Arg::with_name("screenshot")
+            .long("screenshot")
+            .value_name("PNG")
+            .requires("duration")
+            .help("Saves the screen as PNG when the emulation stops"),
+        Arg::with_name("rewind")
+            .short("r")
+            .long("rewind")
+            .value_name("N")
+            .requires("frames")
+            .help("Steps back N frames when the emulation stops, before saving the state"),
+        Arg::with_name("rewind-buffer")
+            .long("rewind-buffer")
+            .value_name("SECONDS")
+            .default_value("10")
+            .help("How many seconds of states are kept for --rewind"),
+        Arg::with_name("input")
+            .short("i")
+            .long("input")
+            .value_name("SCRIPT")
+            .requires("frames")
+            .help("Presses buttons on the frames listed in the input script"),
+        Arg::with_name("record-movie")
+            .long("record-movie")
+            .value_name("FILE")
+            .requires("frames")
+            .help("Records the input of every frame into a movie file"),
+        Arg::with_name("play-movie")
+            .long("play-movie")
+            .value_name("FILE")
+            .conflicts_with_all(&["frames", "cycles", "record-movie", "load-state"])
+            .help("Plays a movie back and fails if the emulation desyncs"),
+        Arg::with_name("debug")
+            .short("d")
+            .long("debug")
+            .conflicts_with_all(&["frames", "cycles", "play-movie"])
+            .help("Starts an interactive debugger instead of running freely"),
+        Arg::with_name("gdb")
+            .long("gdb")
+            .value_name("PORT")
+            .conflicts_with_all(&["frames", "cycles", "play-movie", "debug"])
+            .help("Waits for a GDB remote protocol client on the local port"),
+    ]);
+    args
+}
+
+fn entry_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("entry")
+        .long("entry")
+        .value_name("NAME")
+        .help("File to use from a zip archive, the first .gb or .gbc file by default")
+}
+
+fn patch_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("patch")
+        .long("patch")
+        .value_name("FILE")
+        .multiple(true)
+        .number_of_values(1)
+        .help("IPS, UPS or BPS patch to apply in order, <rom>.ips, .ups and .bps are used by default")
+}
+
+/// The patches given on the command line, or the ones next to the rom
+fn patch_files(matches: &ArgMatches, romname: &str) -> Vec<PathBuf> {
+    match matches.values_of("patch") {
+        Some(paths) => paths.map(PathBuf::from).collect(),
+        None => patch::find(Path::new(romname)),
+    }
+}
+
+fn symbols_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("symbols")
+        .long("symbols")
+        .value_name("FILE")
+        .help("Symbol file with labels, <rom>.sym is used by default")
+}
 
 fn main() {
+    let duration = ArgGroup::with_name("duration").args(&["frames", "cycles"]);
     let matches = App::new("ruBoy")
         .version(crate_version!())
         .author(crate_authors!())
         .about("A Gameboy emulator written in Rust")
-        .arg(
-            Arg::with_name("log")
-                .short("l")
-                .long("log-level")
-                .value_name("LEVEL")
-                .possible_values(&["off", "error", "warn", "debug", "info", "trace", "all"])
-                .default_value("all")
-                .number_of_values(1)
-                .help("The verbosity level of logs"),
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .args(&emulation_args())
+        .group(duration.clone())
+        .subcommand(
+            SubCommand::with_name("run")
+                .about(
+                    "Runs a rom headless for a fixed time, for batch jobs and CI. \
+                     Exits with status 3 if the cpu locked up.",
+                )
+                .args(&emulation_args())
+                .group(duration.required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("disasm")
+                .about("Disassembles a range of a rom bank")
+                .arg(
+                    Arg::with_name("bank")
+                        .short("b")
+                        .long("bank")
+                        .value_name("BANK")
+                        .default_value("0")
+                        .help("Rom bank, bank 0 is at 0x0000 and the others at 0x4000"),
+                )
+                .arg(
+                    Arg::with_name("start")
+                        .long("start")
+                        .value_name("ADDR")
+                        .help("First address in hex, defaults to the start of the bank"),
+                )
+                .arg(
+                    Arg::with_name("end")
+                        .long("end")
+                        .value_name("ADDR")
+                        .help("Last address in hex, defaults to the end of the bank"),
+                )
+                .arg(symbols_arg())
+                .arg(entry_arg())
+                .arg(patch_arg())
+                .arg(
+                    Arg::with_name("rom")
+                        .help("Set the rom file to use")
+                        .required(true)
+                        .index(1),
+                ),
         )
-        .arg(
-            Arg::with_name("rom")
-                .help("Set the rom file to use")
-                .required(true)
-                .index(1),
+        .subcommand(
+            SubCommand::with_name("tracediff")
+                .about("Runs a rom along a reference trace and reports the first divergence")
+                .args(&machine_args())
+                .arg(
+                    Arg::with_name("context")
+                        .long("context")
+                        .value_name("N")
+                        .default_value("10")
+                        .help("Number of instructions shown before the divergence"),
+                )
+                .arg(
+                    Arg::with_name("reference")
+                        .help("Trace in gameboy-doctor format to compare against")
+                        .required(true)
+                        .index(2),
+                ),
         )
         .get_matches();
+    match matches.subcommand() {
+        ("run", Some(run_matches)) => emulate(run_matches),
+        ("disasm", Some(disasm_matches)) => disassemble(disasm_matches),
+        ("tracediff", Some(tracediff_matches)) => trace_diff(tracediff_matches),
+        _ => emulate(&matches),
+    }
+}
+
+/// Sets up logging and a powered on machine from the machine options
+fn power_on(matches: &ArgMatches) -> GameBoy {
     let log_level = matches.value_of("log").unwrap_or("warn");
     let log_level = match log_level {
         "off" => LevelFilter::Off,
@@ -42,10 +264,227 @@ fn main() {
         _ => LevelFilter::Trace,
     };
     WriteLogger::init(log_level, simplelog::Config::default(), stdout()).unwrap();
+    if matches.is_present("trace-log") && !tracelog::set_enabled(true) {
+        warn!("--trace-log needs ruBoy to be built with the trace-log feature");
+    }
 
     let romname = matches.value_of("rom").expect("Rom file need to be specified");
-    let mut memory = Memory::default();
-    let cartridge = memory.load_rom(&romname);
-    let mut cpu = Cpu::new(memory);
-    cpu.run(cartridge);
+    let model = match matches.value_of("model").unwrap_or("auto") {
+        "dmg" => Model::Dmg,
+        "cgb" => Model::Cgb,
+        _ => Model::Auto,
+    };
+    let mut gameboy = GameBoy::new(model);
+    let cartridge = or_exit(
+        gameboy.load_patched_rom_file(
+            Path::new(romname),
+            matches.value_of("entry"),
+            &patch_files(matches, romname),
+        ),
+        &format!("Can not read rom file {}", romname),
+    );
+    println!("loading the '{}'", cartridge.title);
+    let cpu = gameboy.cpu_mut();
+    if let Some(path) = matches.value_of("symbols") {
+        cpu.mem_mut().set_symbols(or_exit(
+            Symbols::load(Path::new(path)),
+            "Can not read the symbols",
+        ));
+    }
+    cpu.mem_mut().set_ly_stub(matches.is_present("stub-ly"));
+    if let Some(path) = matches.value_of("trace") {
+        let file = or_exit(File::create(path), "Can not create the trace file");
+        cpu.set_trace(Box::new(BufWriter::new(file)));
+    }
+    gameboy
+}
+
+fn emulate(matches: &ArgMatches) {
+    let romname = matches.value_of("rom").expect("Rom file need to be specified");
+    let mut gameboy = power_on(matches);
+    let cartridge = gameboy.cartridge().clone();
+
+    let slot = matches.value_of("slot").unwrap_or("0").parse().unwrap_or(0);
+    let slot_path = state::slot_path(romname, slot);
+    if matches.is_present("load-state") {
+        or_exit(
+            state::load(gameboy.cpu_mut(), &cartridge, &slot_path),
+            &format!("Can not load state from {}", slot_path.display()),
+        );
+    }
+
+    if let Some(path) = matches.value_of("play-movie") {
+        let movie = or_exit(Movie::load(Path::new(path)), "Can not read the movie");
+        or_exit(movie.play(gameboy.cpu_mut(), &cartridge), "Movie playback failed");
+        println!("Played {} frames of {} without desync", movie.len(), path);
+    } else if let Some(frames) = matches.value_of("frames") {
+        let frames: u64 = parse_number(frames);
+        let script = matches
+            .value_of("input")
+            .map(|path| or_exit(InputScript::load(Path::new(path)), "Can not read the input script"))
+            .unwrap_or_default();
+        let mut movie = matches.value_of("record-movie").map(|_| {
+            // Movies recorded after --load-state start from that state
+            let start_state = if matches.is_present("load-state") {
+                Some(gameboy.save_state())
+            } else {
+                None
+            };
+            Movie::new(&cartridge, start_state, HASH_INTERVAL)
+        });
+        let mut rewind = matches.value_of("rewind").map(|_| {
+            Rewind::new(parse_number(matches.value_of("rewind-buffer").unwrap_or("10")))
+        });
+
+        for frame in 0..frames {
+            if let Some(rewind) = rewind.as_mut() {
+                rewind.record(gameboy.cpu());
+            }
+            let buttons = script.buttons_at(frame);
+            match movie.as_mut() {
+                Some(movie) => movie.record_frame(gameboy.cpu_mut(), buttons),
+                None => {
+                    gameboy.set_input(buttons);
+                    gameboy.run_frame();
+                }
+            }
+        }
+
+        if let (Some(movie), Some(path)) = (movie, matches.value_of("record-movie")) {
+            or_exit(movie.save(Path::new(path)), "Can not write the movie");
+        }
+        if let (Some(mut rewind), Some(rewind_frames)) = (rewind, matches.value_of("rewind")) {
+            let rewind_frames = parse_number(rewind_frames);
+            info!(
+                "Rewind buffer holds {} frames in {} bytes",
+                rewind.len(),
+                rewind.size()
+            );
+            let stepped = or_exit(
+                rewind.step_back(gameboy.cpu_mut(), rewind_frames),
+                "Can not rewind",
+            );
+            if stepped < rewind_frames {
+                warn!("Could only step back {} frames", stepped);
+            }
+        }
+    } else if let Some(cycles) = matches.value_of("cycles") {
+        gameboy.cpu_mut().run_cycles(parse_number(cycles));
+    } else if let Some(port) = matches.value_of("gdb") {
+        or_exit(
+            GdbStub::default().listen(gameboy.cpu_mut(), parse_number(port)),
+            "GDB session failed",
+        );
+    } else if matches.is_present("debug") {
+        let stdin = io::stdin();
+        or_exit(
+            Debugger::default().repl(gameboy.cpu_mut(), stdin.lock(), stdout()),
+            "Debugger failed",
+        );
+    } else {
+        loop {
+            gameboy.run_frame();
+        }
+    }
+
+    or_exit(gameboy.cpu_mut().flush_trace(), "Can not write the trace");
+    if let Some(lockup) = gameboy.lockup() {
+        eprintln!("{}", lockup);
+    }
+
+    if let Some(path) = matches.value_of("screenshot") {
+        or_exit(
+            screenshot::write_png(Path::new(path), gameboy.framebuffer()),
+            "Can not save the screenshot",
+        );
+    }
+
+    if matches.is_present("save-state") {
+        or_exit(
+            state::save(gameboy.cpu(), &cartridge, &slot_path),
+            &format!("Can not save state to {}", slot_path.display()),
+        );
+    }
+
+    if gameboy.lockup().is_some() {
+        process::exit(LOCKUP_EXIT_STATUS);
+    }
+}
+
+fn trace_diff(matches: &ArgMatches) {
+    let mut gameboy = power_on(matches);
+    let cpu = gameboy.cpu_mut();
+    let reference = matches.value_of("reference").expect("Reference trace need to be specified");
+    let reference = or_exit(File::open(reference), "Can not read the reference trace");
+    let context = parse_number(matches.value_of("context").unwrap_or("10"));
+    let stdout = stdout();
+    let (_, diverged) = or_exit(
+        tracediff::run(cpu, io::BufReader::new(reference), context, &mut stdout.lock()),
+        "Can not compare the traces",
+    );
+    or_exit(cpu.flush_trace(), "Can not write the trace");
+    if let Some(lockup) = cpu.lockup() {
+        eprintln!("{}", lockup);
+    }
+    if diverged {
+        process::exit(1);
+    }
+}
+
+fn disassemble(matches: &ArgMatches) {
+    let romname = matches.value_of("rom").expect("Rom file need to be specified");
+    let rom = or_exit(
+        romfile::read(Path::new(romname), matches.value_of("entry"))
+            .and_then(|rom| patch::apply_files(rom, &patch_files(matches, romname))),
+        "Can not read the rom",
+    );
+    let bank: usize = parse_number(matches.value_of("bank").unwrap_or("0"));
+    if bank * 0x4000 >= rom.len() {
+        eprintln!("The rom only has {} banks", rom.len().div_ceil(0x4000));
+        process::exit(1);
+    }
+    let base = if bank == 0 { 0x0000 } else { 0x4000 };
+    let start = matches.value_of("start").map_or(base, parse_hex);
+    let end = matches.value_of("end").map_or(base + 0x3FFF, parse_hex);
+    let sym_path = match matches.value_of("symbols") {
+        Some(path) => Some(Path::new(path).to_path_buf()),
+        None => Some(Path::new(romname).with_extension("sym")).filter(|path| path.exists()),
+    };
+    let symbols = sym_path
+        .map(|path| or_exit(Symbols::load(&path), "Can not read the symbols"))
+        .unwrap_or_default();
+    // Banked addresses outside of the listed bank resolve to bank 1
+    let bank_of = |addr: u16| match addr {
+        0x4000..=0x7FFF => bank.max(1) as u16,
+        0xD000..=0xDFFF => 1,
+        _ => 0,
+    };
+    for instruction in disasm::rom_listing(&rom, bank, start, end) {
+        if let Some(label) = symbols.label(bank_of(instruction.addr), instruction.addr) {
+            println!("{}:", label);
+        }
+        println!("{:02X}:{}", bank, instruction.listing(&symbols, bank_of));
+    }
+}
+
+fn or_exit<T>(result: io::Result<T>, context: &str) -> T {
+    result.unwrap_or_else(|e| {
+        eprintln!("{}: {}", context, e);
+        process::exit(1);
+    })
+}
+
+fn parse_number<T: std::str::FromStr>(val: &str) -> T {
+    val.parse().unwrap_or_else(|_| {
+        eprintln!("'{}' needs to be a number", val);
+        process::exit(1);
+    })
+}
+
+fn parse_hex(val: &str) -> u16 {
+    let digits = val.trim_start_matches("0x").trim_start_matches('$');
+    u16::from_str_radix(digits, 16).unwrap_or_else(|_| {
+        eprintln!("'{}' needs to be a hex address", val);
+        process::exit(1);
+    })
 }
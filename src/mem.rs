@@ -1,6 +1,22 @@
 #[allow(unused_imports)]
 use log::{debug, error, info, trace, warn};
-use std::{fs, ops::Index};
+use crate::joypad::Joypad;
+use crate::patch;
+use crate::romfile;
+use crate::scheduler::{Event, Scheduler};
+use crate::state::{Savestate, StateReader, StateWriter};
+use crate::symbols::Symbols;
+use crate::timer::Timer;
+use crate::tracelog::trace_event;
+use crate::video::Ppu;
+use crate::watch::{WatchHit, Watchpoint};
+use std::{
+    cell::Cell,
+    convert::TryFrom,
+    io::{self, Error, ErrorKind},
+    ops::Index,
+    path::Path,
+};
 
 #[derive(Copy, Clone)]
 pub enum CartType {
@@ -26,16 +42,18 @@ pub enum CartType {
     Mbc5RumbleBttry = 0x1E,
     Mbc6RamBttry = 0x20,
     Mbc7RamBttryAcclrmtr = 0x22,
-    PocketCamera = 0xFc,
-    BandaiTama5 = 0xFd,
-    Huc3 = 0xFe,
-    Huc1RamBttry = 0xFf,
+    PocketCamera = 0xFC,
+    BandaiTama5 = 0xFD,
+    Huc3 = 0xFE,
+    Huc1RamBttry = 0xFF,
 }
 
-impl From<u8> for CartType {
+impl TryFrom<u8> for CartType {
+    type Error = u8;
+
     // Necessary for conversion from u8 to enum
-    fn from(val: u8) -> Self {
-        match val {
+    fn try_from(val: u8) -> Result<Self, u8> {
+        Ok(match val {
             0x00 => CartType::Rom,
             0x01 => CartType::Mbc1,
             0x02 => CartType::Mbc1Ram,
@@ -58,17 +76,23 @@ impl From<u8> for CartType {
             0x1E => CartType::Mbc5RumbleBttry,
             0x20 => CartType::Mbc6RamBttry,
             0x22 => CartType::Mbc7RamBttryAcclrmtr,
-            0xFc => CartType::PocketCamera,
-            0xFd => CartType::BandaiTama5,
-            0xFe => CartType::Huc3,
-            0xFf => CartType::Huc1RamBttry,
-            _ => {
-                panic!("Could not understand cartridge type");
-            }
-        }
+            0xFC => CartType::PocketCamera,
+            0xFD => CartType::BandaiTama5,
+            0xFE => CartType::Huc3,
+            0xFF => CartType::Huc1RamBttry,
+            _ => return Err(val),
+        })
     }
 }
 
+/// Hardware to emulate, `Auto` picks CGB for games that support it
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Model {
+    Auto,
+    Dmg,
+    Cgb,
+}
+
 // pub fn low_bit(x: u32) -> u8 {
 //     (x & 0xFF) as u8
 // }
@@ -77,23 +101,58 @@ impl From<u8> for CartType {
 //     ((x >> 8) & 0xFF) as u8
 // }
 
-#[allow(dead_code)]
+/// Video ram, bank 1 only exists in CGB mode and holds the second tile
+/// bank and the BG map attributes.
 pub struct Vram {
-    //TODO rewrite to be able to use bank switching
-    charram: [u8; 0x1800], /* 0x8000 - 0x97FF */
-    bgdata1: [u8; 0x400],  /* 0x9800 - 0x9BFF */
-    bgdata2: [u8; 0x400],  /* 0x9C00 - 0x9FFF */
+    banks: [[u8; 0x2000]; 2], /* 0x8000 - 0x9FFF */
+    bank: u8,                 /* VBK at 0xFF4F */
+}
+
+impl Default for Vram {
+    fn default() -> Self {
+        Self {
+            banks: [[0; 0x2000]; 2],
+            bank: 0,
+        }
+    }
+}
+
+impl Savestate for Vram {
+    fn save_state(&self, out: &mut StateWriter) {
+        out.bytes(&self.banks[0]);
+        out.bytes(&self.banks[1]);
+        out.u8(self.bank);
+    }
+
+    fn load_state(&mut self, input: &mut StateReader) -> io::Result<()> {
+        input.bytes(&mut self.banks[0])?;
+        input.bytes(&mut self.banks[1])?;
+        self.bank = input.u8()? & 1;
+        Ok(())
+    }
 }
 
 impl Vram {
-    pub fn write(&self, _addr: u16, _val: u8) {
-        panic!("NOT IMPLEMENTED");
+    pub fn write(&mut self, addr: u16, val: u8) {
+        self.banks[self.bank as usize][addr as usize] = val;
+    }
+    pub fn read(&self, addr: u16) -> u8 {
+        self.read_bank(self.bank, addr)
     }
-    pub fn read(&self, _addr: u16) -> u8 {
-        panic!("NOT IMPLEMENTED");
+    /// Reads from the given bank regardless of VBK, used by the ppu
+    pub fn read_bank(&self, bank: u8, addr: u16) -> u8 {
+        self.banks[bank as usize & 1][addr as usize]
+    }
+    pub fn set_bank(&mut self, val: u8) {
+        self.bank = val & 1;
+    }
+    pub fn bank(&self) -> u8 {
+        self.bank
     }
 }
 
+#[allow(dead_code)]
+#[derive(Clone)]
 pub struct CartHeader {
     //TODO use enum values for the ones that are applicable (necessary?)
     pub logo: Vec<u8>,
@@ -111,11 +170,24 @@ pub struct CartHeader {
     pub use_new_license: bool,
     pub rom_version: u8,
     pub checksum: u8,
+    pub global_checksum: u16,
+    pub specs: CartSpecs,
 }
 
+/* The cartridge header ends at 0x014F */
+const MIN_ROM_SIZE: usize = 0x150;
+
 impl CartHeader {
-    pub fn new(rom: &Vec<u8>) -> Self {
-        let logo = rom.get(0x104..0x134).expect("Can not get logo").to_vec();
+    /// Parses the header, roms that are too short or use an unsupported
+    /// cartridge are refused with `ErrorKind::InvalidData`
+    pub fn new(rom: &[u8]) -> io::Result<Self> {
+        if rom.len() < MIN_ROM_SIZE {
+            return Err(invalid_rom(format!(
+                "Rom is {} bytes, too short for a cartridge header",
+                rom.len()
+            )));
+        }
+        let logo = rom[0x104..0x134].to_vec();
 
         let default_title: String = String::from("Default Title");
         let title = match rom.get(0x134..0x143) {
@@ -129,10 +201,9 @@ impl CartHeader {
                 default_title
             }
         };
-        println!("loading the '{}'", title);
         let gbc_flag = rom.get(0x143).unwrap_or_else(|| {
             error!("Can not understand gbc_flag from rom, using default value");
-            &(0xC0 as u8)
+            &0xC0
         });
         let mut gbc_only = false;
         let mut gbc = false;
@@ -183,9 +254,10 @@ impl CartHeader {
                 "00".to_string()
             }
         };
-        let sgb = *rom.get(0x146).expect("Can not read SGB flag from rom") == 0x3;
-        let cart_type = *rom.get(0x147).expect("Can not read cart type value");
-        let cart_type = CartType::from(cart_type);
+        let sgb = rom[0x146] == 0x3;
+        let cart_type = CartType::try_from(rom[0x147]).map_err(|val| {
+            invalid_rom(format!("Cartridge type 0x{:02X} is not understood", val))
+        })?;
         let mut specs = CartSpecs::default();
         match cart_type {
             CartType::Rom => specs.rom_only = true,
@@ -278,8 +350,11 @@ impl CartHeader {
         }
         let size: u16 = match rom[0x148] {
             x if x < 9 => 0x8000 << x,
-            0x52 | 0x53 | 0x54 => {
-                panic!("This rom size is not supported at the moment");
+            x @ 0x52..=0x54 => {
+                return Err(invalid_rom(format!(
+                    "Rom size 0x{:02X} is not supported at the moment",
+                    x
+                )));
             }
             _ => {
                 error!("Rom size is not understood, using default rom size of 32Kb");
@@ -302,7 +377,7 @@ impl CartHeader {
         let japan_code = rom[0x14A];
         let old_license = rom[0x14B];
         if old_license != 0x33 && sgb {
-            panic!("Super GameBoy functions won't work");
+            warn!("Super GameBoy functions won't work");
         }
         let use_new_license = old_license == 0x33;
         if use_new_license {
@@ -312,41 +387,25 @@ impl CartHeader {
         }
         let rom_version = rom[0x14C];
         // Checksum function is x=0:FOR i=0134h TO 014Ch:x=x-MEM[i]-1:NEXT
-        #[cfg(checksum)]
-        {
-            let mut x: u8 = 0;
-            for i in 0x134..=0x14C {
-                log_write(
-                    LogLevel::Message,
-                    &format!("Using value 0x{:x} for processing checksum", rom[i]),
-                );
-                x = x.wrapping_sub(rom[i].wrapping_sub(1));
-            }
-
-            let checksum = rom[0x14D];
-            if (x & 0xff) != checksum {
-                log_write(
-                    LogLevel::Warning,
-                    "Checksums doesnt match, ignoring checksums anyway",
-                );
-                log_write(
-                    LogLevel::Warning,
-                    &format!(
-                        "Checksums:\n\tCalculated:0x{:x} Found:0x{:x}",
-                        (x & 0xFF),
-                        checksum
-                    ),
-                );
-            } else {
-                log_write(LogLevel::Message, "Checksums matches.");
-            }
+        let mut x: u8 = 0;
+        for byte in &rom[0x134..=0x14C] {
+            x = x.wrapping_sub(*byte).wrapping_sub(1);
+        }
+        let checksum = rom[0x14D];
+        if x != checksum {
+            warn!("Checksums doesnt match, ignoring checksums anyway");
+            warn!(
+                "Checksums:\n\tCalculated:0x{:x} Found:0x{:x}",
+                x, checksum
+            );
+        } else {
+            info!("Checksums matches.");
         }
-        #[cfg(not(checksum))]
-        let checksum = 0;
 
-        // Ignoring global checksum altogether
+        // Global checksum is not verified, only kept to identify the rom
+        let global_checksum = ((rom[0x14E] as u16) << 8) | rom[0x14F] as u16;
 
-        Self {
+        Ok(Self {
             logo,
             title,
             manufact,
@@ -362,24 +421,31 @@ impl CartHeader {
             use_new_license,
             rom_version,
             checksum,
-        }
+            global_checksum,
+            specs,
+        })
     }
 }
 
-#[derive(Default)]
-struct CartSpecs {
-    rom_only: bool,
-    mbc: u8,
-    battery: bool,
-    ram: bool,
-    mmm01: bool,
-    timer: bool,
-    rumble: bool,
-    accelerometer: bool,
-    pocket_camera: bool,
-    bandai: bool,
-    huc3: bool,
-    huc1: bool,
+fn invalid_rom(msg: String) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+#[allow(dead_code)]
+#[derive(Default, Clone)]
+pub struct CartSpecs {
+    pub rom_only: bool,
+    pub mbc: u8,
+    pub battery: bool,
+    pub ram: bool,
+    pub mmm01: bool,
+    pub timer: bool,
+    pub rumble: bool,
+    pub accelerometer: bool,
+    pub pocket_camera: bool,
+    pub bandai: bool,
+    pub huc3: bool,
+    pub huc1: bool,
 }
 
 // pub fn check_logo() {
@@ -405,13 +471,13 @@ impl From<Vec<u8>> for Rom {
 impl Index<u16> for Rom {
     type Output = u8;
     fn index(&self, index: u16) -> &Self::Output {
-        &self.read(index)
+        self.read(index)
     }
 }
 
 impl Rom {
     pub fn new(rom: Vec<u8>) -> Self {
-        Self { rom: rom, bank: 0 }
+        Self { rom, bank: 0 }
     }
 
     #[allow(dead_code)]
@@ -419,11 +485,46 @@ impl Rom {
         self.bank = index;
     }
 
+    /// Addresses past the end of a short rom read as 0xFF, like an open bus
     pub fn read(&self, addr: u16) -> &u8 {
-        match addr {
-            a @ 0x0000..=0x3FFF => &self.rom[a as usize],
-            a => &self.rom[self.bank as usize * 0x4000 + a as usize],
-        }
+        let index = match addr {
+            a @ 0x0000..=0x3FFF => a as usize,
+            a => self.bank as usize * 0x4000 + a as usize,
+        };
+        self.rom.get(index).unwrap_or(&0xFF)
+    }
+}
+
+/// CGB VRAM DMA, copies 16 byte blocks either all at once (general purpose)
+/// or one block at the start of every HBlank
+#[derive(Default)]
+pub struct Hdma {
+    src: u16,     /* HDMA1 - HDMA2 */
+    dst: u16,     /* HDMA3 - HDMA4, offset into VRAM */
+    length: u8,   /* HDMA5 bits 0-6, remaining blocks - 1 */
+    active: bool, /* HBlank DMA in progress */
+}
+
+impl Savestate for Hdma {
+    fn save_state(&self, out: &mut StateWriter) {
+        out.u16(self.src);
+        out.u16(self.dst);
+        out.u8(self.length);
+        out.bool(self.active);
+    }
+
+    fn load_state(&mut self, input: &mut StateReader) -> io::Result<()> {
+        self.src = input.u16()?;
+        self.dst = input.u16()? & 0x1FF0;
+        self.length = input.u8()? & 0x7F;
+        self.active = input.bool()?;
+        Ok(())
+    }
+}
+
+impl Hdma {
+    pub fn read_hdma5(&self) -> u8 {
+        ((!self.active as u8) << 7) | self.length
     }
 }
 
@@ -432,66 +533,463 @@ pub struct Memory {
     vram: Vram,          /* 0x8000 - 0x9FFF */
     sram: [u8; 0x2000],  /* 0xA000 - 0xBFFF */
     wram0: [u8; 0x1000], /* 0xC000 - 0xCFFF */
-    wramx: [u8; 0x1000], /* 0xD000 - 0xDFFF */
+    wramx: [[u8; 0x1000]; 7], /* 0xD000 - 0xDFFF, banks 1-7 */
+    wram_bank: u8,       /* SVBK at 0xFF70 */
     oam: [u8; 0xa0],     /* 0xFE00 - 0xFE9F */
     ioregs: [u8; 0x80],  /* 0xFF00 - 0xFF7F */
     hram: [u8; 0x7f],    /* 0xFF80 - 0xFFFE */
     ie_reg: [u8; 0x1],   /* 0xFFFF */
+    ppu: Ppu,
+    timer: Timer,
+    joypad: Joypad,
+    hdma: Hdma,
+    scheduler: Scheduler,
+    stall: u32,         /* cycles the cpu is halted for by DMA or a speed switch */
+    double_speed: bool, /* KEY1 bit 7 */
+    speed_switch: bool, /* KEY1 bit 0, armed for the next STOP */
+    model: Model,
+    cgb: bool,          /* CGB mode, the game uses colour features */
+    cgb_hardware: bool, /* CGB mode or DMG compatibility mode */
+    watchpoints: Vec<Watchpoint>,
+    watch_hit: Cell<Option<WatchHit>>, /* set from `read8`, which takes &self */
+    symbols: Symbols,
+    ly_stub: bool, /* LY always reads 0x90, like gameboy-doctor expects */
 }
 
 impl Default for Memory {
     fn default() -> Self {
-        Self {
+        let mut mem = Self {
             rom: Rom::from(vec![0; 0x8000]),
-            vram: Vram {
-                charram: [0; 0x1800],
-                bgdata1: [0; 0x400],
-                bgdata2: [0; 0x400],
-            },
+            vram: Vram::default(),
             sram: [0; 0x2000],
             wram0: [0; 0x1000],
-            wramx: [0; 0x1000],
+            wramx: [[0; 0x1000]; 7],
+            wram_bank: 1,
             oam: [0; 0xa0],
             ioregs: [0; 0x80],
             hram: [0; 0x7f],
             ie_reg: [0; 0x1],
+            ppu: Ppu::default(),
+            timer: Timer::default(),
+            joypad: Joypad::default(),
+            hdma: Hdma::default(),
+            scheduler: Scheduler::default(),
+            stall: 0,
+            double_speed: false,
+            speed_switch: false,
+            model: Model::Auto,
+            cgb: false,
+            cgb_hardware: false,
+            watchpoints: Vec::new(),
+            watch_hit: Cell::new(None),
+            symbols: Symbols::default(),
+            ly_stub: false,
+        };
+        mem.schedule(Event::Ppu);
+        mem.schedule(Event::Timer);
+        mem
+    }
+}
+
+impl Savestate for Memory {
+    fn save_state(&self, out: &mut StateWriter) {
+        out.u8(self.rom.bank);
+        self.vram.save_state(out);
+        out.bytes(&self.sram);
+        out.bytes(&self.wram0);
+        for bank in self.wramx.iter() {
+            out.bytes(bank);
+        }
+        out.u8(self.wram_bank);
+        out.bytes(&self.oam);
+        out.bytes(&self.ioregs);
+        out.bytes(&self.hram);
+        out.bytes(&self.ie_reg);
+        self.ppu.save_state(out);
+        self.timer.save_state(out);
+        self.joypad.save_state(out);
+        self.hdma.save_state(out);
+        self.scheduler.save_state(out);
+        out.u32(self.stall);
+        out.bool(self.double_speed);
+        out.bool(self.speed_switch);
+        out.bool(self.cgb);
+        out.bool(self.cgb_hardware);
+    }
+
+    fn load_state(&mut self, input: &mut StateReader) -> io::Result<()> {
+        self.rom.bank = input.u8()?;
+        self.vram.load_state(input)?;
+        input.bytes(&mut self.sram)?;
+        input.bytes(&mut self.wram0)?;
+        for bank in self.wramx.iter_mut() {
+            input.bytes(bank)?;
         }
+        self.wram_bank = match input.u8()? & 0x7 {
+            0 => 1,
+            bank => bank,
+        };
+        input.bytes(&mut self.oam)?;
+        input.bytes(&mut self.ioregs)?;
+        input.bytes(&mut self.hram)?;
+        input.bytes(&mut self.ie_reg)?;
+        self.ppu.load_state(input)?;
+        self.timer.load_state(input)?;
+        self.joypad.load_state(input)?;
+        self.hdma.load_state(input)?;
+        self.scheduler.load_state(input)?;
+        self.stall = input.u32()?;
+        self.double_speed = input.bool()?;
+        self.speed_switch = input.bool()?;
+        self.cgb = input.bool()?;
+        self.cgb_hardware = input.bool()?;
+        Ok(())
     }
 }
 
 impl Memory {
-    pub fn load_rom(&mut self, fname: &str) -> CartHeader {
-        let rom_bytes = fs::read(fname).expect(&format!("Can not read rom file: {}", fname));
-        info!("Number of bytes read from rom: {}", rom_bytes.len());
-        self.rom = Rom::new(rom_bytes.clone());
-        CartHeader::new(&rom_bytes)
+    /// Loads a rom file with its patches and symbols
+    pub fn load_rom(&mut self, fname: &str) -> io::Result<CartHeader> {
+        let path = Path::new(fname);
+        let rom = romfile::read(path, None)?;
+        let header = self.load_rom_bytes(patch::apply_files(rom, &patch::find(path))?)?;
+        self.load_rom_symbols(path);
+        Ok(header)
+    }
+
+    /// Inserts the cartridge, the model needs to be set before
+    pub fn load_rom_bytes(&mut self, rom_bytes: Vec<u8>) -> io::Result<CartHeader> {
+        let header = CartHeader::new(&rom_bytes)?;
+        self.rom = Rom::new(rom_bytes);
+        let cgb_hardware = match self.model {
+            Model::Auto => header.gbc,
+            Model::Dmg => false,
+            Model::Cgb => true,
+        };
+        self.set_cgb(cgb_hardware && header.gbc);
+        self.cgb_hardware = cgb_hardware;
+        self.ppu.set_compat(cgb_hardware && !header.gbc);
+        Ok(header)
+    }
+
+    /// Loads `<rom>.sym` if there is one next to the rom
+    pub fn load_rom_symbols(&mut self, rom_path: &Path) {
+        let sym_path = rom_path.with_extension("sym");
+        if sym_path.exists() {
+            match Symbols::load(&sym_path) {
+                Ok(symbols) => {
+                    info!("Loaded {} symbols from {}", symbols.len(), sym_path.display());
+                    self.symbols = symbols;
+                }
+                Err(e) => warn!("Can not read symbols from {}: {}", sym_path.display(), e),
+            }
+        }
+    }
+
+    pub fn symbols(&self) -> &Symbols {
+        &self.symbols
+    }
+
+    pub fn set_symbols(&mut self, symbols: Symbols) {
+        self.symbols = symbols;
+    }
+
+    /// Makes LY read 0x90, as if the ppu was always in VBlank, so traces
+    /// can be compared with gameboy-doctor logs
+    pub fn set_ly_stub(&mut self, ly_stub: bool) {
+        self.ly_stub = ly_stub;
+    }
+
+    /// Bank mapped at the address, as numbered in symbol files
+    pub fn bank_at(&self, addr: u16) -> u16 {
+        match addr {
+            0x4000..=0x7FFF => self.rom.bank as u16 + 1,
+            0x8000..=0x9FFF => self.vram.bank() as u16,
+            0xD000..=0xDFFF => self.wramx_bank() as u16 + 1,
+            _ => 0,
+        }
+    }
+
+    /// Needs to be set before loading the rom
+    pub fn set_model(&mut self, model: Model) {
+        self.model = model;
+    }
+
+    pub fn set_cgb(&mut self, cgb: bool) {
+        self.cgb = cgb;
+        self.cgb_hardware |= cgb;
+        self.ppu.set_cgb(cgb);
+    }
+
+    pub fn cgb_hardware(&self) -> bool {
+        self.cgb_hardware
+    }
+
+    /// Index into `wramx` of the bank mapped to 0xD000 - 0xDFFF,
+    /// DMG mode always uses bank 1
+    fn wramx_bank(&self) -> usize {
+        if self.cgb {
+            self.wram_bank as usize - 1
+        } else {
+            0
+        }
+    }
+
+    /// Sets the pressed buttons, see the bits in `joypad`
+    pub fn set_input(&mut self, buttons: u8) {
+        self.ioregs[0x0F] |= self.joypad.set_buttons(buttons);
+    }
+
+    pub fn framebuffer(&self) -> &[u16] {
+        self.ppu.framebuffer()
+    }
+
+    /// Number of frames the ppu completed
+    pub fn frame(&self) -> u64 {
+        self.ppu.frame()
+    }
+
+    /// Advances the clock by the cycles the cpu spent, the other components
+    /// only run when their next event is due
+    #[inline]
+    pub fn tick(&mut self, cycles: u32) {
+        if self.scheduler.advance(cycles) {
+            while let Some(event) = self.scheduler.pop_due() {
+                self.sync_component(event);
+            }
+        }
+    }
+
+    /// Catches up the component behind the address, so the cpu sees it as
+    /// if it had been running all along
+    pub fn sync(&mut self, addr: u16) {
+        match addr {
+            0xFF04..=0xFF07 => self.sync_component(Event::Timer),
+            0x8000..=0x9FFF
+            | 0xFE00..=0xFE9F
+            | 0xFF40..=0xFF4B
+            | 0xFF4F
+            | 0xFF51..=0xFF55
+            | 0xFF68..=0xFF6B => self.sync_component(Event::Ppu),
+            // Interrupt flags are set by every component
+            0xFF0F | 0xFF4D => self.sync_all(),
+            _ => {}
+        }
+    }
+
+    /// Catches up every component, before inspecting the whole state
+    pub fn sync_all(&mut self) {
+        self.sync_component(Event::Ppu);
+        self.sync_component(Event::Timer);
+    }
+
+    /// Advances a component to the current cycle and schedules its next event.
+    /// The timer follows the cpu clock while the ppu and HDMA keep the normal speed.
+    fn sync_component(&mut self, event: Event) {
+        let cycles = self.scheduler.sync(event);
+        match event {
+            Event::Timer => self.ioregs[0x0F] |= self.timer.step(cycles),
+            Event::Ppu => {
+                let cycles = if self.double_speed { cycles / 2 } else { cycles };
+                let irq = self.ppu.step(cycles as u32, &self.vram, &self.oam);
+                self.ioregs[0x0F] |= irq;
+                for _ in 0..self.ppu.take_hblanks() {
+                    if self.hdma.active {
+                        self.hdma_block();
+                    }
+                }
+            }
+        }
+        self.schedule(event);
+    }
+
+    /// Schedules the next event of an up to date component
+    fn schedule(&mut self, event: Event) {
+        match event {
+            Event::Timer => match self.timer.next_event() {
+                Some(cycles) => self.scheduler.schedule_in(event, cycles),
+                None => self.scheduler.cancel(event),
+            },
+            Event::Ppu => {
+                let cycles = self.ppu.next_event() as u64;
+                let cycles = if self.double_speed { cycles * 2 } else { cycles };
+                self.scheduler.schedule_in(event, cycles);
+            }
+        }
+    }
+
+    /// Called on STOP, switches the cpu speed if it was requested through KEY1
+    pub fn switch_speed(&mut self) -> bool {
+        if !self.cgb || !self.speed_switch {
+            return false;
+        }
+        self.sync_all();
+        self.double_speed = !self.double_speed;
+        self.speed_switch = false;
+        self.timer.reset_div();
+        self.schedule(Event::Ppu);
+        self.schedule(Event::Timer);
+        // The cpu is paused for 2050 M-cycles while the clock settles
+        self.stall += 8200;
+        info!("Switched to {} speed", if self.double_speed { "double" } else { "normal" });
+        true
+    }
+
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) -> usize {
+        self.watchpoints.push(watchpoint);
+        self.watchpoints.len() - 1
+    }
+
+    pub fn remove_watchpoint(&mut self, index: usize) -> Option<Watchpoint> {
+        if index < self.watchpoints.len() {
+            Some(self.watchpoints.remove(index))
+        } else {
+            None
+        }
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    /// First watchpoint triggered since the last call, resets it
+    pub fn take_watch_hit(&mut self) -> Option<WatchHit> {
+        self.watch_hit.take()
+    }
+
+    /// Kept out of line so `read8` and `write` only pay for an empty check
+    #[cold]
+    fn check_watchpoints(&self, addr: u16, value: u8, write: bool) {
+        if self.watch_hit.get().is_some() {
+            return;
+        }
+        let index = self
+            .watchpoints
+            .iter()
+            .position(|watch| watch.matches(addr, value, write));
+        if let Some(index) = index {
+            self.watch_hit.set(Some(WatchHit {
+                index,
+                addr,
+                value,
+                write,
+            }));
+        }
+    }
+
+    /// Cycles the cpu has to wait for DMA transfers, resets the counter
+    pub fn take_stall(&mut self) -> u32 {
+        std::mem::take(&mut self.stall)
+    }
+
+    fn write_hdma5(&mut self, val: u8) {
+        if self.hdma.active && val & 0x80 == 0 {
+            // Cancels the HBlank DMA, the remaining length stays readable
+            self.hdma.active = false;
+            return;
+        }
+        self.hdma.length = val & 0x7F;
+        if val & 0x80 != 0 {
+            self.hdma.active = true;
+        } else {
+            // General purpose DMA halts the cpu until everything is copied
+            while self.hdma_block() {}
+        }
+    }
+
+    /// Copies one 16 byte block to VRAM, returns if there are blocks left
+    fn hdma_block(&mut self) -> bool {
+        for i in 0..0x10 {
+            let val = self.peek8(self.hdma.src.wrapping_add(i));
+            self.vram.write((self.hdma.dst + i) & 0x1FFF, val);
+        }
+        self.hdma.src = self.hdma.src.wrapping_add(0x10);
+        self.hdma.dst += 0x10;
+        // A block takes 8 M-cycles, twice as many cpu cycles in double speed
+        self.stall += if self.double_speed { 64 } else { 32 };
+        self.hdma.length = self.hdma.length.wrapping_sub(1) & 0x7F;
+        let done = self.hdma.length == 0x7F || self.hdma.dst >= 0x2000;
+        if done {
+            self.hdma.length = 0x7F;
+            self.hdma.active = false;
+        }
+        !done
     }
 
     pub fn read8(&self, addr: u16) -> u8 {
-        let val = match addr {
+        let val = self.peek8(addr);
+        if !self.watchpoints.is_empty() {
+            self.check_watchpoints(addr, val, false);
+            // Like writes, echo ram reads are also seen at the work ram address
+            if let 0xE000..=0xFDFF = addr {
+                self.check_watchpoints(addr - 0x2000, val, false);
+            }
+        }
+        trace_event!("Value 0x{:x} read from 0x{:X}", val, addr);
+        val
+    }
+
+    /// Reads an instruction byte, which does not trigger read watchpoints
+    pub fn fetch8(&self, addr: u16) -> u8 {
+        let val = self.peek8(addr);
+        trace_event!("Value 0x{:x} fetched from 0x{:X}", val, addr);
+        val
+    }
+
+    /// Reads like the cpu would, without triggering watchpoints or logging
+    pub fn peek8(&self, addr: u16) -> u8 {
+        match addr {
             0x0000..=0x7fff => self.rom[addr],
             0x8000..=0x9FFF => self.vram.read(addr - 0x8000),
             0xA000..=0xBFFF => self.sram[(addr - 0xA000) as usize],
             0xC000..=0xCFFF => self.wram0[(addr - 0xC000) as usize],
-            0xD000..=0xDFFF => self.wramx[(addr - 0xD000) as usize],
-            0xe000..=0xFDFF => self.read8(addr - 0x2000),
+            0xD000..=0xDFFF => self.wramx[self.wramx_bank()][(addr - 0xD000) as usize],
+            0xe000..=0xFDFF => self.peek8(addr - 0x2000),
             0xFE00..=0xFE9F => self.oam[(addr - 0xFE00) as usize],
             0xFEA0..=0xFEFF => 0,
-            0xFF00..=0xFF7F => self.ioregs[(addr - 0xFF00) as usize],
-            0xFF00..=0xFFFE => self.hram[(addr - 0xFF80) as usize],
+            0xFF00..=0xFF7F => self.read_io(addr),
+            0xFF80..=0xFFFE => self.hram[(addr - 0xFF80) as usize],
             0xFFFF => self.ie_reg[0],
-        };
-        info!("Value 0x{:x} read from 0x{:X}", val, addr);
-        val
+        }
     }
-    pub fn read16(&self, addr: u16) -> u16 {
-        ((self.read8(addr + 1) as u16) << 8) | self.read8(addr) as u16
+
+    fn read_io(&self, addr: u16) -> u8 {
+        match addr {
+            0xFF00 => self.joypad.read(),
+            0xFF04..=0xFF07 => self.timer.read(addr),
+            0xFF44 if self.ly_stub => 0x90,
+            0xFF40..=0xFF45 | 0xFF47..=0xFF4B | 0xFF68..=0xFF6B => self.ppu.read(addr),
+            0xFF4D if self.cgb => {
+                ((self.double_speed as u8) << 7) | 0x7E | self.speed_switch as u8
+            }
+            0xFF4D => 0xFF,
+            0xFF4F if self.cgb => 0xFE | self.vram.bank(),
+            0xFF4F => 0xFF,
+            0xFF51..=0xFF54 => 0xFF,
+            0xFF55 if self.cgb => self.hdma.read_hdma5(),
+            0xFF55 => 0xFF,
+            0xFF70 if self.cgb => 0xF8 | self.wram_bank,
+            0xFF70 => 0xFF,
+            _ => self.ioregs[(addr - 0xFF00) as usize],
+        }
+    }
+
+    /// Writes like the cpu would, without triggering watchpoints, for the
+    /// debuggers editing memory
+    pub fn poke8(&mut self, addr: u16, val: u8) {
+        let hit = self.watch_hit.take();
+        self.write(addr, val);
+        self.watch_hit.set(hit);
     }
 
     pub fn write(&mut self, addr: u16, val: u8) {
+        if !self.watchpoints.is_empty() {
+            self.check_watchpoints(addr, val, true);
+        }
+        self.sync(addr);
         match addr {
             0x0000..=0x7fff => {
-                warn!("Ignoring write to ROM address {}", addr);
+                trace_event!("Ignoring write to ROM address {}", addr);
             }
             0x8000..=0x9FFF => {
                 self.vram.write(addr - 0x8000, val);
@@ -503,7 +1001,8 @@ impl Memory {
                 self.wram0[(addr - 0xC000) as usize] = val;
             }
             0xD000..=0xDFFF => {
-                self.wramx[(addr - 0xD000) as usize] = val;
+                let bank = self.wramx_bank();
+                self.wramx[bank][(addr - 0xD000) as usize] = val;
             }
             0xe000..=0xFDFF => {
                 self.write(addr - 0x2000, val);
@@ -512,29 +1011,270 @@ impl Memory {
                 self.oam[(addr - 0xFE00) as usize] = val;
             }
             0xFEA0..=0xFEFF => {
-                info!(
+                trace_event!(
                     "Ignoring write to unused range 0xFEA0-0xFEFF, requested address was 0x{:X}",
                     addr
                 );
                 return;
             }
             0xFF00..=0xFF7F => {
-                self.ioregs[(addr - 0xFF00) as usize] = val;
+                self.write_io(addr, val);
             }
-            0xFF00..=0xFFFE => {
+            0xFF80..=0xFFFE => {
                 self.hram[(addr - 0xFF80) as usize] = val;
             }
             0xFFFF => {
                 self.ie_reg[0] = val;
             }
         }
-        info!("0x{:x} written to memory address 0x{:X}", val, addr);
+        trace_event!("0x{:x} written to memory address 0x{:X}", val, addr);
+    }
+
+    fn write_io(&mut self, addr: u16, val: u8) {
+        match addr {
+            0xFF00 => self.joypad.write(val),
+            0xFF04..=0xFF07 => {
+                self.timer.write(addr, val);
+                self.schedule(Event::Timer);
+            }
+            0xFF40..=0xFF45 | 0xFF47..=0xFF4B | 0xFF68..=0xFF6B => {
+                self.ppu.write(addr, val);
+                self.schedule(Event::Ppu);
+            }
+            0xFF4D if self.cgb => self.speed_switch = val & 1 != 0,
+            0xFF4D => info!("Ignoring KEY1 write outside of CGB mode"),
+            0xFF4F if self.cgb => self.vram.set_bank(val),
+            0xFF4F => info!("Ignoring VBK write outside of CGB mode"),
+            0xFF51..=0xFF55 if !self.cgb => info!("Ignoring HDMA write outside of CGB mode"),
+            0xFF51 => self.hdma.src = (self.hdma.src & 0x00F0) | (val as u16) << 8,
+            0xFF52 => self.hdma.src = (self.hdma.src & 0xFF00) | (val & 0xF0) as u16,
+            0xFF53 => self.hdma.dst = (self.hdma.dst & 0x00F0) | ((val & 0x1F) as u16) << 8,
+            0xFF54 => self.hdma.dst = (self.hdma.dst & 0x1F00) | (val & 0xF0) as u16,
+            0xFF55 => self.write_hdma5(val),
+            0xFF70 if self.cgb => {
+                // Bank 0 can not be selected, it maps to bank 1
+                self.wram_bank = if val & 0x7 == 0 { 1 } else { val & 0x7 };
+            }
+            0xFF70 => info!("Ignoring SVBK write outside of CGB mode"),
+            _ => self.ioregs[(addr - 0xFF00) as usize] = val,
+        }
     }
+}
 
-    pub fn write16(&mut self, addr: u16, val: u16) {
-        let ls_byte = val as u8 & 0xff;
-        let ms_byte = (val >> 8) as u8;
-        self.write(addr, ls_byte);
-        self.write(addr + 1, ms_byte);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::timer::INT_TIMER;
+
+    #[test]
+    fn vram_bank_switching() {
+        let mut mem = Memory::default();
+        mem.set_cgb(true);
+        mem.write(0x8000, 0x12);
+        mem.write(0xFF4F, 0x01);
+        assert!(mem.read8(0xFF4F) == 0xFF);
+        assert!(mem.read8(0x8000) == 0x00);
+        mem.write(0x8000, 0x34);
+        mem.write(0xFF4F, 0x00);
+        assert!(mem.read8(0xFF4F) == 0xFE);
+        assert!(mem.read8(0x8000) == 0x12);
+    }
+
+    #[test]
+    fn vram_bank_fixed_on_dmg() {
+        let mut mem = Memory::default();
+        mem.write(0x9800, 0x12);
+        mem.write(0xFF4F, 0x01);
+        assert!(mem.read8(0xFF4F) == 0xFF);
+        assert!(mem.read8(0x9800) == 0x12);
+    }
+
+    #[test]
+    fn wram_bank_switching() {
+        let mut mem = Memory::default();
+        mem.set_cgb(true);
+        for bank in 1..=7 {
+            mem.write(0xFF70, bank);
+            mem.write(0xD000, bank * 0x10);
+        }
+        mem.write(0xFF70, 3);
+        assert!(mem.read8(0xFF70) == 0xFB);
+        assert!(mem.read8(0xD000) == 0x30);
+        // Echo ram mirrors the selected bank
+        assert!(mem.read8(0xF000) == 0x30);
+        mem.write(0xF000, 0x33);
+        assert!(mem.read8(0xD000) == 0x33);
+        // Bank 0 selects bank 1
+        mem.write(0xFF70, 0);
+        assert!(mem.read8(0xFF70) == 0xF9);
+        assert!(mem.read8(0xD000) == 0x10);
+    }
+
+    #[test]
+    fn wram_bank_fixed_on_dmg() {
+        let mut mem = Memory::default();
+        mem.write(0xD000, 0x12);
+        mem.write(0xFF70, 0x05);
+        assert!(mem.read8(0xFF70) == 0xFF);
+        assert!(mem.read8(0xD000) == 0x12);
+        assert!(mem.read8(0xF000) == 0x12);
+    }
+
+    fn setup_hdma(mem: &mut Memory) {
+        mem.set_cgb(true);
+        for i in 0..0x40 {
+            mem.write(0xC000 + i, i as u8);
+        }
+        mem.write(0xFF51, 0xC0);
+        mem.write(0xFF52, 0x0F); // low nibble is ignored
+        mem.write(0xFF53, 0xE1); // upper bits are ignored, 0x8100
+        mem.write(0xFF54, 0x00);
+    }
+
+    #[test]
+    fn general_purpose_dma() {
+        let mut mem = Memory::default();
+        setup_hdma(&mut mem);
+        mem.write(0xFF55, 0x02);
+        assert!(mem.read8(0x8100) == 0x00);
+        assert!(mem.read8(0x812F) == 0x2F);
+        assert!(mem.read8(0x8130) == 0x00);
+        assert!(mem.read8(0xFF55) == 0xFF);
+        assert!(mem.take_stall() == 3 * 32);
+        assert!(mem.take_stall() == 0);
+    }
+
+    #[test]
+    fn hblank_dma() {
+        let mut mem = Memory::default();
+        setup_hdma(&mut mem);
+        mem.write(0xFF55, 0x82);
+        assert!(mem.read8(0xFF55) == 0x02);
+        assert!(mem.read8(0x8100) == 0x00 && mem.read8(0x8101) == 0x00);
+        // First HBlank starts after OAM scan and drawing
+        mem.tick(80 + 172);
+        assert!(mem.read8(0x810F) == 0x0F);
+        assert!(mem.read8(0x8110) == 0x00);
+        assert!(mem.read8(0xFF55) == 0x01);
+        assert!(mem.take_stall() == 32);
+        mem.tick(456);
+        assert!(mem.read8(0x811F) == 0x1F);
+        // Cancelling keeps the remaining length with bit 7 set
+        mem.write(0xFF55, 0x00);
+        assert!(mem.read8(0xFF55) == 0x80);
+        mem.tick(456);
+        assert!(mem.read8(0x8120) == 0x00);
+    }
+
+    #[test]
+    fn speed_switch() {
+        let mut mem = Memory::default();
+        mem.set_cgb(true);
+        assert!(mem.read8(0xFF4D) == 0x7E);
+        assert!(!mem.switch_speed());
+        mem.write(0xFF4D, 0x01);
+        assert!(mem.read8(0xFF4D) == 0x7F);
+        assert!(mem.switch_speed());
+        assert!(mem.read8(0xFF4D) == 0xFE);
+        assert!(mem.take_stall() == 8200);
+        // The ppu keeps its speed, a line takes twice as many cpu cycles
+        mem.tick(456);
+        assert!(mem.read8(0xFF44) == 0);
+        mem.tick(456);
+        assert!(mem.read8(0xFF44) == 1);
+        // The timer follows the cpu, it is caught up when accessed
+        mem.sync(0xFF04);
+        assert!(mem.read8(0xFF04) == 3);
+    }
+
+    #[test]
+    fn timer_interrupt_is_scheduled() {
+        let mut mem = Memory::default();
+        mem.write(0xFF05, 0xFF);
+        mem.write(0xFF07, 0x5); // enabled, every 16 cycles
+        mem.tick(12);
+        assert!(mem.read8(0xFF0F) & INT_TIMER == 0);
+        // The overflow runs the timer without the cpu accessing it
+        mem.tick(4);
+        assert!(mem.read8(0xFF0F) & INT_TIMER != 0);
+        assert!(mem.read8(0xFF05) == 0x00);
+    }
+
+    #[test]
+    fn no_speed_switch_on_dmg() {
+        let mut mem = Memory::default();
+        mem.write(0xFF4D, 0x01);
+        assert!(mem.read8(0xFF4D) == 0xFF);
+        assert!(!mem.switch_speed());
+    }
+
+    #[test]
+    fn watchpoints() {
+        use crate::watch::Access;
+        let mut mem = Memory::default();
+        mem.add_watchpoint(Watchpoint {
+            start: 0xC0A0,
+            end: 0xC0A0,
+            access: Access::Write,
+            value: None,
+        });
+        mem.add_watchpoint(Watchpoint {
+            start: 0xFF80,
+            end: 0xFFFE,
+            access: Access::Read,
+            value: Some(0x42),
+        });
+        mem.write(0xC0A1, 0x01);
+        mem.read8(0xC0A0);
+        assert!(mem.take_watch_hit().is_none());
+        // The echo of work ram writes the same memory
+        mem.write(0xE0A0, 0x02);
+        let hit = mem.take_watch_hit().unwrap();
+        assert!(hit.index == 0 && hit.addr == 0xC0A0 && hit.write);
+        mem.write(0xFF90, 0x42);
+        assert!(mem.peek8(0xFF90) == 0x42);
+        assert!(mem.take_watch_hit().is_none());
+        assert!(mem.read8(0xFF90) == 0x42);
+        assert!(mem.take_watch_hit().unwrap().index == 1);
+        assert!(mem.remove_watchpoint(0).is_some());
+        mem.write(0xC0A0, 0x03);
+        assert!(mem.take_watch_hit().is_none());
+    }
+
+    #[test]
+    fn echo_ram_watchpoints() {
+        use crate::watch::Access;
+        let mut mem = Memory::default();
+        mem.add_watchpoint(Watchpoint {
+            start: 0xC0A0,
+            end: 0xC0A0,
+            access: Access::ReadWrite,
+            value: None,
+        });
+        mem.read8(0xE0A0);
+        let hit = mem.take_watch_hit().unwrap();
+        assert!(hit.addr == 0xC0A0 && !hit.write);
+        mem.write(0xE0A0, 0x01);
+        let hit = mem.take_watch_hit().unwrap();
+        assert!(hit.addr == 0xC0A0 && hit.write);
+        mem.fetch8(0xE0A0);
+        mem.fetch8(0xC0A0);
+        assert!(mem.take_watch_hit().is_none());
+    }
+
+    #[test]
+    fn dma_does_not_hit_watchpoints() {
+        use crate::watch::Access;
+        let mut mem = Memory::default();
+        setup_hdma(&mut mem);
+        mem.add_watchpoint(Watchpoint {
+            start: 0xC000,
+            end: 0xC03F,
+            access: Access::Read,
+            value: None,
+        });
+        mem.write(0xFF55, 0x02);
+        assert!(mem.peek8(0x812F) == 0x2F);
+        assert!(mem.take_watch_hit().is_none());
     }
 }
@@ -0,0 +1,312 @@
+use crate::cpu::Cpu;
+use crate::joypad::BUTTON_NAMES;
+use crate::mem::CartHeader;
+use crate::state::{self, StateReader, StateWriter};
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use std::{
+    fs,
+    io::{self, Error, ErrorKind},
+    path::Path,
+};
+
+const MAGIC: &[u8; 8] = b"UBOYMOVI";
+pub const MOVIE_VERSION: u16 = 1;
+/* Frames between the state hashes used to detect desyncs */
+pub const HASH_INTERVAL: u32 = 60;
+
+/// Per frame joypad input recorded from power on or from a save state,
+/// with periodic hashes of the machine state to detect desyncs on playback
+pub struct Movie {
+    emulator: String, /* version of ruBoy the movie was recorded with */
+    title: String,
+    checksum: u8,
+    global_checksum: u16,
+    start_state: Vec<u8>, /* save state to start from, empty for power on */
+    hash_interval: u32,
+    inputs: Vec<u8>,
+    hashes: Vec<u64>, /* state hash after every `hash_interval` frames */
+}
+
+impl Movie {
+    pub fn new(cart: &CartHeader, start_state: Option<Vec<u8>>, hash_interval: u32) -> Self {
+        Self {
+            emulator: env!("CARGO_PKG_VERSION").to_string(),
+            title: cart.title.clone(),
+            checksum: cart.checksum,
+            global_checksum: cart.global_checksum,
+            start_state: start_state.unwrap_or_default(),
+            hash_interval: hash_interval.max(1),
+            inputs: Vec::new(),
+            hashes: Vec::new(),
+        }
+    }
+
+    /// Number of recorded frames
+    pub fn len(&self) -> usize {
+        self.inputs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inputs.is_empty()
+    }
+
+    /// Runs a frame with the given buttons pressed and records it
+    pub fn record_frame(&mut self, cpu: &mut Cpu, buttons: u8) {
+        cpu.mem_mut().set_input(buttons);
+        cpu.run_frames(1);
+        self.inputs.push(buttons);
+        if self.inputs.len() % self.hash_interval as usize == 0 {
+            self.hashes.push(state_hash(cpu));
+        }
+    }
+
+    /// Replays the movie on a machine that was just powered on. Fails if
+    /// the movie is for another rom or the machine state diverges.
+    pub fn play(&self, cpu: &mut Cpu, cart: &CartHeader) -> io::Result<()> {
+        if self.title != cart.title
+            || self.checksum != cart.checksum
+            || self.global_checksum != cart.global_checksum
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "Movie was recorded for '{}', not the loaded rom",
+                    self.title
+                ),
+            ));
+        }
+        if self.emulator != env!("CARGO_PKG_VERSION") {
+            warn!(
+                "Movie was recorded with ruBoy {}, it may desync on {}",
+                self.emulator,
+                env!("CARGO_PKG_VERSION")
+            );
+        }
+        if !self.start_state.is_empty() {
+            state::load_bytes(cpu, cart, &self.start_state)?;
+        }
+        for (frame, buttons) in self.inputs.iter().enumerate() {
+            cpu.mem_mut().set_input(*buttons);
+            cpu.run_frames(1);
+            let frame = frame + 1;
+            if frame % self.hash_interval as usize != 0 {
+                continue;
+            }
+            let expected = self.hashes.get(frame / self.hash_interval as usize - 1);
+            if expected.is_some_and(|hash| *hash != state_hash(cpu)) {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Movie desynced at frame {}", frame),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = StateWriter::default();
+        out.bytes(MAGIC);
+        out.u16(MOVIE_VERSION);
+        for text in &[&self.emulator, &self.title] {
+            out.u8(text.len() as u8);
+            out.bytes(text.as_bytes());
+        }
+        out.u8(self.checksum);
+        out.u16(self.global_checksum);
+        out.u32(self.start_state.len() as u32);
+        out.bytes(&self.start_state);
+        out.u32(self.hash_interval);
+        out.u32(self.inputs.len() as u32);
+        out.bytes(&self.inputs);
+        out.u32(self.hashes.len() as u32);
+        for hash in &self.hashes {
+            out.u64(*hash);
+        }
+        out.into_bytes()
+    }
+
+    pub fn from_bytes(data: &[u8]) -> io::Result<Self> {
+        let mut input = StateReader::new(data);
+        let mut magic = [0; 8];
+        input.bytes(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(Error::new(ErrorKind::InvalidData, "Not a movie file"));
+        }
+        let version = input.u16()?;
+        if version != MOVIE_VERSION {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Movie version {} is not supported", version),
+            ));
+        }
+        let read_text = |input: &mut StateReader| -> io::Result<String> {
+            let len = input.u8()? as usize;
+            let text = input.vec(len)?;
+            Ok(String::from_utf8_lossy(&text).into_owned())
+        };
+        let emulator = read_text(&mut input)?;
+        let title = read_text(&mut input)?;
+        let checksum = input.u8()?;
+        let global_checksum = input.u16()?;
+        let len = input.u32()? as usize;
+        let start_state = input.vec(len)?;
+        let hash_interval = input.u32()?.max(1);
+        let len = input.u32()? as usize;
+        let inputs = input.vec(len)?;
+        let hashes = (0..input.u32()?)
+            .map(|_| input.u64())
+            .collect::<io::Result<Vec<u64>>>()?;
+        Ok(Self {
+            emulator,
+            title,
+            checksum,
+            global_checksum,
+            start_state,
+            hash_interval,
+            inputs,
+            hashes,
+        })
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        fs::write(path, self.to_bytes())
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self> {
+        Self::from_bytes(&fs::read(path)?)
+    }
+}
+
+/// FNV-1a hash of the machine state
+pub fn state_hash(cpu: &Cpu) -> u64 {
+    state::snapshot(cpu)
+        .iter()
+        .fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+            (hash ^ *byte as u64).wrapping_mul(0x0100_0000_01b3)
+        })
+}
+
+/// Buttons to press on given frames, one `<frame> <buttons>` entry per line
+/// where buttons are joined with `+` like `120 start+a`, or `none`. The
+/// buttons stay pressed until the next entry, `#` starts a comment.
+#[derive(Default)]
+pub struct InputScript {
+    changes: Vec<(u64, u8)>,
+}
+
+impl InputScript {
+    pub fn parse(text: &str) -> io::Result<Self> {
+        let mut changes = Vec::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let invalid = |reason: &str| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Input script line {}: {}", number + 1, reason),
+                )
+            };
+            let mut fields = line.split_whitespace();
+            let frame = fields
+                .next()
+                .and_then(|frame| frame.parse::<u64>().ok())
+                .ok_or_else(|| invalid("frame needs to be a number"))?;
+            let mut buttons = 0;
+            for name in fields.next().unwrap_or("none").split('+') {
+                if name == "none" {
+                    continue;
+                }
+                buttons |= BUTTON_NAMES
+                    .iter()
+                    .find(|(button, _)| button.eq_ignore_ascii_case(name))
+                    .map(|(_, bit)| *bit)
+                    .ok_or_else(|| invalid(&format!("unknown button '{}'", name)))?;
+            }
+            changes.push((frame, buttons));
+        }
+        changes.sort_by_key(|(frame, _)| *frame);
+        Ok(Self { changes })
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    /// Buttons pressed during the given frame
+    pub fn buttons_at(&self, frame: u64) -> u8 {
+        self.changes
+            .iter()
+            .take_while(|(start, _)| *start <= frame)
+            .last()
+            .map_or(0, |(_, buttons)| *buttons)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::joypad::{A, START};
+    use crate::mem::Memory;
+
+    fn powered_on() -> (Cpu, CartHeader) {
+        let cart = CartHeader::new(&[0; 0x8000]).unwrap();
+        let mut cpu = Cpu::new(Memory::default());
+        cpu.initialize(&cart);
+        (cpu, cart)
+    }
+
+    #[test]
+    fn input_script() {
+        let script =
+            InputScript::parse("# title screen\n10 start\n\n12 a+START # both\n20 none\n").unwrap();
+        assert!(script.buttons_at(0) == 0);
+        assert!(script.buttons_at(11) == START);
+        assert!(script.buttons_at(12) == A | START);
+        assert!(script.buttons_at(100) == 0);
+        assert!(InputScript::parse("10 turbo").is_err());
+        assert!(InputScript::parse("start").is_err());
+    }
+
+    #[test]
+    fn record_and_play() {
+        let (mut cpu, cart) = powered_on();
+        let mut movie = Movie::new(&cart, None, 1);
+        movie.record_frame(&mut cpu, START);
+        movie.record_frame(&mut cpu, 0);
+        let recorded = state_hash(&cpu);
+        let movie = Movie::from_bytes(&movie.to_bytes()).unwrap();
+        assert!(movie.len() == 2);
+
+        let (mut cpu, cart) = powered_on();
+        movie.play(&mut cpu, &cart).unwrap();
+        assert!(state_hash(&cpu) == recorded);
+    }
+
+    #[test]
+    fn detects_desync() {
+        let (mut cpu, cart) = powered_on();
+        let mut movie = Movie::new(&cart, None, 1);
+        movie.record_frame(&mut cpu, 0);
+        movie.record_frame(&mut cpu, 0);
+
+        let (mut cpu, cart) = powered_on();
+        cpu.mem_mut().write(0xC000, 0x42);
+        let err = movie.play(&mut cpu, &cart).unwrap_err();
+        assert!(err.to_string() == "Movie desynced at frame 1");
+    }
+
+    #[test]
+    fn huge_lengths_are_truncated() {
+        let (_, cart) = powered_on();
+        let movie = Movie::new(&cart, None, 1);
+        let mut data = movie.to_bytes();
+        /* Length of the start state, after the magic, version, texts and checksums */
+        let pos = 8 + 2 + 1 + movie.emulator.len() + 1 + movie.title.len() + 1 + 2;
+        data[pos..pos + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        let err = Movie::from_bytes(&data).err().unwrap();
+        assert!(err.kind() == ErrorKind::UnexpectedEof);
+    }
+}
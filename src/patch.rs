@@ -0,0 +1,411 @@
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use std::{
+    convert::TryInto,
+    fs,
+    io::{self, Error, ErrorKind},
+    path::{Path, PathBuf},
+};
+
+/* Extensions of the patches applied automatically, in this order */
+const EXTENSIONS: [&str; 3] = ["ips", "ups", "bps"];
+/* The largest cartridges are 8MB, bigger sizes in a patch are bogus */
+const MAX_ROM_SIZE: usize = 8 << 20;
+
+/// `<rom>.ips`, `<rom>.ups` and `<rom>.bps` next to the rom
+pub fn find(rom_path: &Path) -> Vec<PathBuf> {
+    EXTENSIONS
+        .iter()
+        .map(|extension| rom_path.with_extension(extension))
+        .filter(|path| path.exists())
+        .collect()
+}
+
+/// Applies the patch files one after the other
+pub fn apply_files(mut rom: Vec<u8>, paths: &[PathBuf]) -> io::Result<Vec<u8>> {
+    for path in paths {
+        let patch = fs::read(path)?;
+        rom = apply(rom, &patch)
+            .map_err(|e| Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+        info!("Applied patch {}", path.display());
+    }
+    Ok(rom)
+}
+
+/// Applies an IPS, UPS or BPS patch, recognized by its magic bytes. UPS and
+/// BPS patches are refused for other roms than the one they were made for.
+pub fn apply(rom: Vec<u8>, patch: &[u8]) -> io::Result<Vec<u8>> {
+    if patch.starts_with(b"PATCH") {
+        apply_ips(rom, patch)
+    } else if patch.starts_with(b"UPS1") {
+        apply_ups(&rom, patch)
+    } else if patch.starts_with(b"BPS1") {
+        apply_bps(&rom, patch)
+    } else {
+        Err(invalid("Not an IPS, UPS or BPS patch".to_string()))
+    }
+}
+
+/// Records of a 24 bit offset, a 16 bit size and the data, a size of 0
+/// repeats a single byte. The file ends with `EOF` and optionally the
+/// size to truncate the rom to.
+fn apply_ips(mut rom: Vec<u8>, patch: &[u8]) -> io::Result<Vec<u8>> {
+    let mut input = Reader::new(&patch[5..]);
+    loop {
+        let offset = input.bytes(3)?;
+        if offset == b"EOF" {
+            break;
+        }
+        let offset = be(offset);
+        let size = be(input.bytes(2)?);
+        if size == 0 {
+            let count = be(input.bytes(2)?);
+            let value = input.byte()?;
+            grow(&mut rom, offset + count)?;
+            rom[offset..offset + count].fill(value);
+        } else {
+            let data = input.bytes(size)?;
+            grow(&mut rom, offset + size)?;
+            rom[offset..offset + size].copy_from_slice(data);
+        }
+    }
+    if let Ok(size) = input.bytes(3) {
+        rom.truncate(be(size));
+    }
+    Ok(rom)
+}
+
+/// Hunks of bytes XORed with the rom, each after a number of unchanged
+/// bytes and ended by a zero
+fn apply_ups(source: &[u8], patch: &[u8]) -> io::Result<Vec<u8>> {
+    let (body, target_crc) = check_crcs("UPS", source, patch)?;
+    let mut input = Reader::new(&body[4..]);
+    let source_size = input.number()?;
+    let target_size = input.number()?;
+    check_sizes(source, source_size, target_size)?;
+    let mut target = source.to_vec();
+    target.resize(target_size, 0);
+    let mut pos = 0;
+    while !input.is_empty() {
+        pos += input.number()?;
+        loop {
+            let xor = input.byte()?;
+            if let Some(byte) = target.get_mut(pos) {
+                *byte ^= xor;
+            }
+            pos += 1;
+            if xor == 0 {
+                break;
+            }
+        }
+    }
+    check_target("UPS", &target, target_crc)?;
+    Ok(target)
+}
+
+/// Actions copying runs of bytes from the rom, the patch or the already
+/// patched output
+fn apply_bps(source: &[u8], patch: &[u8]) -> io::Result<Vec<u8>> {
+    let (body, target_crc) = check_crcs("BPS", source, patch)?;
+    let mut input = Reader::new(&body[4..]);
+    let source_size = input.number()?;
+    let target_size = input.number()?;
+    check_sizes(source, source_size, target_size)?;
+    let metadata = input.number()?;
+    input.bytes(metadata)?;
+
+    let out_of_range = || invalid("BPS patch copies from outside of the rom".to_string());
+    let mut target = Vec::with_capacity(target_size);
+    let (mut source_offset, mut target_offset) = (0, 0);
+    while !input.is_empty() {
+        let action = input.number()?;
+        let length = (action >> 2) + 1;
+        /* Checked before anything is copied so a huge length can't grow the
+         * output past the rom size */
+        if length > target_size - target.len() {
+            return Err(invalid(
+                "BPS patch writes past the end of the rom".to_string(),
+            ));
+        }
+        match action & 0x3 {
+            0 => {
+                /* SourceRead, the rom at the same position */
+                let start = target.len();
+                let data = source.get(start..start + length).ok_or_else(out_of_range)?;
+                target.extend_from_slice(data);
+            }
+            1 => {
+                /* TargetRead, bytes in the patch */
+                target.extend_from_slice(input.bytes(length)?);
+            }
+            2 => {
+                /* SourceCopy, the rom at a relative position */
+                source_offset =
+                    relative(source_offset, input.number()?).ok_or_else(out_of_range)?;
+                let data = source
+                    .get(source_offset..source_offset + length)
+                    .ok_or_else(out_of_range)?;
+                target.extend_from_slice(data);
+                source_offset += length;
+            }
+            _ => {
+                /* TargetCopy, the output at a relative position, byte by
+                 * byte as the run can overlap with what it writes */
+                target_offset =
+                    relative(target_offset, input.number()?).ok_or_else(out_of_range)?;
+                for _ in 0..length {
+                    let byte = *target.get(target_offset).ok_or_else(out_of_range)?;
+                    target.push(byte);
+                    target_offset += 1;
+                }
+            }
+        }
+    }
+    check_target("BPS", &target, target_crc)?;
+    Ok(target)
+}
+
+/// Checks the CRC32 of the patch itself and of the rom it is applied to.
+/// Returns the patch without the CRCs at its end and the CRC32 of the result.
+fn check_crcs<'a>(kind: &str, source: &[u8], patch: &'a [u8]) -> io::Result<(&'a [u8], u32)> {
+    if patch.len() < 4 + 12 {
+        return Err(truncated());
+    }
+    let (body, footer) = patch.split_at(patch.len() - 12);
+    let crc = |at: usize| u32::from_le_bytes(footer[at..at + 4].try_into().unwrap());
+    if crc32fast::hash(&patch[..patch.len() - 4]) != crc(8) {
+        return Err(invalid(format!("{} patch is corrupted", kind)));
+    }
+    if crc32fast::hash(source) != crc(0) {
+        return Err(invalid(format!(
+            "{} patch is made for a different rom",
+            kind
+        )));
+    }
+    Ok((body, crc(4)))
+}
+
+fn check_sizes(source: &[u8], source_size: usize, target_size: usize) -> io::Result<()> {
+    if source_size != source.len() {
+        return Err(invalid(format!(
+            "Patch expects a rom of {} bytes, not {}",
+            source_size,
+            source.len()
+        )));
+    }
+    if target_size > MAX_ROM_SIZE {
+        return Err(invalid(format!(
+            "Patched rom would be {} bytes",
+            target_size
+        )));
+    }
+    Ok(())
+}
+
+fn check_target(kind: &str, target: &[u8], crc: u32) -> io::Result<()> {
+    if crc32fast::hash(target) != crc {
+        return Err(invalid(format!(
+            "{} patch produced a rom with the wrong CRC",
+            kind
+        )));
+    }
+    Ok(())
+}
+
+/// Offsets of copies are relative to the end of the previous one, the low
+/// bit is the sign
+fn relative(offset: usize, data: usize) -> Option<usize> {
+    if data & 1 != 0 {
+        offset.checked_sub(data >> 1)
+    } else {
+        offset.checked_add(data >> 1)
+    }
+}
+
+/// Big endian number of an IPS record
+fn be(bytes: &[u8]) -> usize {
+    bytes
+        .iter()
+        .fold(0, |val, &byte| (val << 8) | byte as usize)
+}
+
+fn grow(rom: &mut Vec<u8>, len: usize) -> io::Result<()> {
+    if len > MAX_ROM_SIZE {
+        return Err(invalid(format!("Patched rom would be {} bytes", len)));
+    }
+    if rom.len() < len {
+        rom.resize(len, 0);
+    }
+    Ok(())
+}
+
+fn invalid(msg: String) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+fn truncated() -> Error {
+    Error::new(ErrorKind::UnexpectedEof, "Patch is truncated")
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    fn bytes(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if len > self.data.len() {
+            return Err(truncated());
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> io::Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    /// Variable length number of UPS and BPS, 7 bits per byte with the
+    /// high bit set on the last one
+    fn number(&mut self) -> io::Result<usize> {
+        let mut val: usize = 0;
+        let mut shift: usize = 1;
+        loop {
+            let byte = self.byte()?;
+            val = (byte as usize & 0x7F)
+                .checked_mul(shift)
+                .and_then(|add| val.checked_add(add))
+                .ok_or_else(|| invalid("Number in the patch is too large".to_string()))?;
+            if byte & 0x80 != 0 {
+                return Ok(val);
+            }
+            shift = shift
+                .checked_shl(7)
+                .filter(|shift| *shift <= MAX_ROM_SIZE << 7)
+                .ok_or_else(|| invalid("Number in the patch is too large".to_string()))?;
+            val += shift;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn number(mut val: usize, out: &mut Vec<u8>) {
+        loop {
+            let byte = (val & 0x7F) as u8;
+            val >>= 7;
+            if val == 0 {
+                out.push(0x80 | byte);
+                return;
+            }
+            out.push(byte);
+            val -= 1;
+        }
+    }
+
+    fn with_crcs(mut patch: Vec<u8>, source: &[u8], target: &[u8]) -> Vec<u8> {
+        patch.extend(&crc32fast::hash(source).to_le_bytes());
+        patch.extend(&crc32fast::hash(target).to_le_bytes());
+        let crc = crc32fast::hash(&patch);
+        patch.extend(&crc.to_le_bytes());
+        patch
+    }
+
+    #[test]
+    fn numbers() {
+        for &val in &[0, 1, 0x7F, 0x80, 0x4080, 0x12345, MAX_ROM_SIZE] {
+            let mut data = Vec::new();
+            number(val, &mut data);
+            assert!(Reader::new(&data).number().unwrap() == val);
+        }
+    }
+
+    #[test]
+    fn ips() {
+        let mut patch = b"PATCH".to_vec();
+        patch.extend(&[0x00, 0x00, 0x01, 0x00, 0x02, 0xAA, 0xBB]);
+        // Repeated byte past the end grows the rom
+        patch.extend(&[0x00, 0x00, 0x06, 0x00, 0x00, 0x00, 0x03, 0xCC]);
+        patch.extend(b"EOF");
+        let rom = apply(vec![0; 4], &patch).unwrap();
+        assert!(rom == [0x00, 0xAA, 0xBB, 0x00, 0x00, 0x00, 0xCC, 0xCC, 0xCC]);
+        // Truncation after the end marker
+        patch.extend(&[0x00, 0x00, 0x02]);
+        assert!(apply(vec![0; 4], &patch).unwrap() == [0x00, 0xAA]);
+        assert!(apply(vec![0; 4], &patch[..10]).is_err());
+    }
+
+    #[test]
+    fn ups() {
+        let source = [1, 2, 3, 4, 5, 6];
+        let target = [1, 9, 3, 4, 5, 6, 7, 8];
+        let mut patch = b"UPS1".to_vec();
+        number(source.len(), &mut patch);
+        number(target.len(), &mut patch);
+        number(1, &mut patch);
+        patch.extend(&[2 ^ 9, 0]);
+        number(3, &mut patch);
+        patch.extend(&[7, 8, 0]);
+        let patch = with_crcs(patch, &source, &target);
+        assert!(apply(source.to_vec(), &patch).unwrap() == target);
+
+        let other = apply(vec![0; 6], &patch).unwrap_err();
+        assert!(other.to_string() == "UPS patch is made for a different rom");
+        let mut corrupted = patch.clone();
+        corrupted[7] ^= 1;
+        assert!(apply(source.to_vec(), &corrupted).is_err());
+    }
+
+    #[test]
+    fn bps() {
+        let source = b"ABCDEFGH";
+        let target = b"ABCDxyxyxyGHEF";
+        let mut patch = b"BPS1".to_vec();
+        number(source.len(), &mut patch);
+        number(target.len(), &mut patch);
+        number(3, &mut patch);
+        patch.extend(b"uby");
+        let action = |kind: usize, length: usize, patch: &mut Vec<u8>| {
+            number(((length - 1) << 2) | kind, patch)
+        };
+        action(0, 4, &mut patch); // ABCD from the source
+        action(1, 2, &mut patch); // xy from the patch
+        patch.extend(b"xy");
+        action(3, 4, &mut patch); // xyxy overlapping from target offset 4
+        number(4 << 1, &mut patch);
+        action(2, 2, &mut patch); // GH from source offset 6
+        number(6 << 1, &mut patch);
+        action(2, 2, &mut patch); // EF, 4 bytes back from offset 8
+        number((4 << 1) | 1, &mut patch);
+        let patch = with_crcs(patch, source, target);
+        assert!(apply(source.to_vec(), &patch).unwrap() == target);
+        assert!(apply(b"ABCDEFGX".to_vec(), &patch).is_err());
+    }
+
+    #[test]
+    fn bps_length_past_target() {
+        let source = b"ABCD";
+        let mut patch = b"BPS1".to_vec();
+        number(source.len(), &mut patch);
+        number(source.len(), &mut patch);
+        number(0, &mut patch);
+        number(1, &mut patch); // A from the patch
+        patch.push(b'A');
+        /* TargetCopy repeating the A 4GB times, far past the rom size */
+        number(((1 << 32) << 2) | 3, &mut patch);
+        number(0, &mut patch);
+        let patch = with_crcs(patch, source, source);
+        let err = apply(source.to_vec(), &patch).err().unwrap();
+        assert!(err.to_string() == "BPS patch writes past the end of the rom");
+    }
+}
@@ -0,0 +1,241 @@
+use crate::cpu::Cpu;
+use crate::state;
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use std::{collections::VecDeque, io};
+
+pub const FRAMES_PER_SECOND: usize = 60;
+/* Every keyframe is followed by this many deltas against it */
+const KEYFRAME_INTERVAL: usize = 60;
+
+enum Snapshot {
+    Key(Vec<u8>),
+    /* Run length encoded XOR against the keyframe of its group */
+    Delta(Vec<u8>),
+}
+
+/// Ring buffer of the last frames' machine states. States are grouped into
+/// a full keyframe followed by deltas against it, so memory stays bounded
+/// by roughly one keyframe per second plus the bytes that actually changed.
+pub struct Rewind {
+    capacity: usize, /* frames that can be stepped back */
+    snapshots: VecDeque<Snapshot>,
+}
+
+impl Rewind {
+    pub fn new(seconds: usize) -> Self {
+        Self::with_capacity(seconds * FRAMES_PER_SECOND)
+    }
+
+    pub fn with_capacity(frames: usize) -> Self {
+        Self {
+            capacity: frames.max(1),
+            snapshots: VecDeque::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.snapshots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.snapshots.is_empty()
+    }
+
+    /// Bytes used by the stored snapshots
+    pub fn size(&self) -> usize {
+        self.snapshots
+            .iter()
+            .map(|s| match s {
+                Snapshot::Key(bytes) | Snapshot::Delta(bytes) => bytes.len(),
+            })
+            .sum()
+    }
+
+    /// Records the state of the machine, meant to be called once per frame
+    pub fn record(&mut self, cpu: &Cpu) {
+        self.push(state::snapshot(cpu));
+    }
+
+    pub fn push(&mut self, state: Vec<u8>) {
+        let snapshot = match self.keyframe() {
+            Some((index, key))
+                if self.snapshots.len() - index < KEYFRAME_INTERVAL && key.len() == state.len() =>
+            {
+                Snapshot::Delta(encode_delta(key, &state))
+            }
+            _ => Snapshot::Key(state),
+        };
+        self.snapshots.push_back(snapshot);
+
+        /* Drop whole groups from the front, deltas can't outlive their keyframe */
+        loop {
+            let group = self
+                .snapshots
+                .iter()
+                .skip(1)
+                .position(|s| matches!(s, Snapshot::Key(_)))
+                .map_or(self.snapshots.len(), |i| i + 1);
+            if self.snapshots.len() - group < self.capacity {
+                break;
+            }
+            self.snapshots.drain(..group);
+        }
+    }
+
+    /// Removes and returns the newest state
+    pub fn pop(&mut self) -> Option<Vec<u8>> {
+        let state = match self.snapshots.back()? {
+            Snapshot::Key(state) => state.clone(),
+            Snapshot::Delta(delta) => {
+                let (_, key) = self.keyframe()?;
+                decode_delta(key, delta)
+            }
+        };
+        self.snapshots.pop_back();
+        Some(state)
+    }
+
+    /// Steps the machine back by the given number of recorded frames,
+    /// returns how many frames it actually went back
+    pub fn step_back(&mut self, cpu: &mut Cpu, frames: usize) -> io::Result<usize> {
+        let mut stepped = 0;
+        let mut state = None;
+        while stepped < frames && !self.is_empty() {
+            state = self.pop();
+            stepped += 1;
+        }
+        if let Some(state) = state {
+            state::restore(cpu, &state)?;
+        }
+        Ok(stepped)
+    }
+
+    /// The newest keyframe and its position in the buffer
+    fn keyframe(&self) -> Option<(usize, &[u8])> {
+        self.snapshots
+            .iter()
+            .enumerate()
+            .rev()
+            .find_map(|(i, s)| match s {
+                Snapshot::Key(key) => Some((i, key.as_slice())),
+                Snapshot::Delta(_) => None,
+            })
+    }
+}
+
+fn write_varint(out: &mut Vec<u8>, mut val: usize) {
+    while val >= 0x80 {
+        out.push(val as u8 | 0x80);
+        val >>= 7;
+    }
+    out.push(val as u8);
+}
+
+fn read_varint(data: &[u8], pos: &mut usize) -> usize {
+    let mut val = 0;
+    let mut shift = 0;
+    while let Some(byte) = data.get(*pos) {
+        *pos += 1;
+        val |= ((byte & 0x7F) as usize) << shift;
+        if byte & 0x80 == 0 {
+            break;
+        }
+        shift += 7;
+    }
+    val
+}
+
+/// XORs `state` against `key` and stores it as pairs of
+/// (unchanged byte count, changed byte count, changed bytes)
+fn encode_delta(key: &[u8], state: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < state.len() {
+        let start = i;
+        while i < state.len() && state[i] == key[i] {
+            i += 1;
+        }
+        let same = i - start;
+        let start = i;
+        while i < state.len() && state[i] != key[i] {
+            i += 1;
+        }
+        write_varint(&mut out, same);
+        write_varint(&mut out, i - start);
+        out.extend(
+            state[start..i]
+                .iter()
+                .zip(&key[start..i])
+                .map(|(s, k)| s ^ k),
+        );
+    }
+    out
+}
+
+fn decode_delta(key: &[u8], delta: &[u8]) -> Vec<u8> {
+    let mut state = key.to_vec();
+    let mut pos = 0;
+    let mut i = 0;
+    while pos < delta.len() {
+        i += read_varint(delta, &mut pos);
+        let changed = read_varint(delta, &mut pos);
+        for byte in &delta[pos..pos + changed] {
+            state[i] ^= byte;
+            i += 1;
+        }
+        pos += changed;
+    }
+    state
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem::Memory;
+
+    #[test]
+    fn delta_round_trip() {
+        let key: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+        let mut state = key.clone();
+        state[0] = 0xFF;
+        state[500..700].iter_mut().for_each(|b| *b = 0x42);
+        state[999] = 0;
+        let delta = encode_delta(&key, &state);
+        assert!(delta.len() < 250);
+        assert!(decode_delta(&key, &delta) == state);
+        assert!(encode_delta(&key, &key).len() == 3);
+    }
+
+    #[test]
+    fn keeps_last_frames() {
+        let mut rewind = Rewind::with_capacity(100);
+        for frame in 0..300u32 {
+            let mut state = vec![0; 64];
+            state[..4].copy_from_slice(&frame.to_le_bytes());
+            rewind.push(state);
+        }
+        assert!(rewind.len() >= 100 && rewind.len() < 100 + KEYFRAME_INTERVAL);
+        for frame in (200..300u32).rev() {
+            let state = rewind.pop().unwrap();
+            assert!(state[..4] == frame.to_le_bytes());
+        }
+    }
+
+    #[test]
+    fn steps_machine_back() {
+        let mut cpu = Cpu::new(Memory::default());
+        let mut rewind = Rewind::new(1);
+        for _ in 0..3 {
+            rewind.record(&cpu);
+            cpu.step();
+        }
+        let last = state::snapshot(&cpu);
+        rewind.record(&cpu);
+        cpu.step();
+        assert!(rewind.step_back(&mut cpu, 1).unwrap() == 1);
+        assert!(state::snapshot(&cpu) == last);
+        assert!(rewind.step_back(&mut cpu, 10).unwrap() == 3);
+        assert!(rewind.is_empty());
+    }
+}
@@ -0,0 +1,146 @@
+use flate2::read::GzDecoder;
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use std::{
+    borrow::Cow,
+    fs,
+    io::{self, Cursor, Error, ErrorKind, Read},
+    path::Path,
+};
+use zip::{result::ZipError, ZipArchive};
+
+const GZIP_MAGIC: &[u8] = &[0x1F, 0x8B];
+const ZIP_MAGIC: &[u8] = b"PK\x03\x04";
+
+/// Reads a rom file, which can also be gzip compressed or a zip archive
+pub fn read(path: &Path, entry: Option<&str>) -> io::Result<Vec<u8>> {
+    let data = fs::read(path)?;
+    info!("Number of bytes read from rom: {}", data.len());
+    // Plain roms are moved out of the buffer instead of being copied
+    match decode(&data, entry)? {
+        Cow::Borrowed(_) => Ok(data),
+        Cow::Owned(rom) => Ok(rom),
+    }
+}
+
+/// The rom in a gzip stream or zip archive, recognized by their magic
+/// bytes, anything else is returned as it is. In a zip archive the named
+/// entry is used, otherwise the first .gb or .gbc file.
+pub fn decode<'a>(data: &'a [u8], entry: Option<&str>) -> io::Result<Cow<'a, [u8]>> {
+    if data.starts_with(GZIP_MAGIC) {
+        let mut rom = Vec::new();
+        GzDecoder::new(data).read_to_end(&mut rom)?;
+        info!("Decompressed {} bytes of gzip data", rom.len());
+        Ok(Cow::Owned(rom))
+    } else if data.starts_with(ZIP_MAGIC) {
+        unzip(data, entry).map(Cow::Owned)
+    } else {
+        if let Some(entry) = entry {
+            warn!("Ignoring entry {}, the rom is not a zip archive", entry);
+        }
+        Ok(Cow::Borrowed(data))
+    }
+}
+
+fn unzip(data: &[u8], entry: Option<&str>) -> io::Result<Vec<u8>> {
+    let mut archive = ZipArchive::new(Cursor::new(data)).map_err(invalid)?;
+    let mut file = match entry {
+        Some(name) => archive.by_name(name).map_err(|e| match e {
+            ZipError::FileNotFound => Error::new(
+                ErrorKind::NotFound,
+                format!("The archive has no entry named {}", name),
+            ),
+            e => invalid(e),
+        })?,
+        None => {
+            let index = (0..archive.len())
+                .find(|&index| {
+                    archive
+                        .by_index_raw(index)
+                        .is_ok_and(|file| file.is_file() && is_rom_name(file.name()))
+                })
+                .ok_or_else(|| {
+                    Error::new(
+                        ErrorKind::InvalidData,
+                        "The archive has no .gb or .gbc file",
+                    )
+                })?;
+            archive.by_index(index).map_err(invalid)?
+        }
+    };
+    let mut rom = Vec::with_capacity(file.size() as usize);
+    file.read_to_end(&mut rom)?;
+    info!("Extracted {} bytes from {}", rom.len(), file.name());
+    Ok(rom)
+}
+
+fn is_rom_name(name: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    name.ends_with(".gb") || name.ends_with(".gbc")
+}
+
+fn invalid(e: ZipError) -> Error {
+    Error::new(ErrorKind::InvalidData, e)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::{write::GzEncoder, Compression};
+    use std::io::Write;
+    use zip::{write::FileOptions, ZipWriter};
+
+    fn zip(entries: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, data) in entries {
+            writer.start_file(*name, FileOptions::default()).unwrap();
+            writer.write_all(data).unwrap();
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn plain_rom_is_not_copied() {
+        let rom = [0x00, 0xC3, 0x50, 0x01];
+        assert!(matches!(decode(&rom, None).unwrap(), Cow::Borrowed(data) if data == rom));
+    }
+
+    #[test]
+    fn gzip() {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&[0x42; 0x8000]).unwrap();
+        let data = encoder.finish().unwrap();
+        assert!(decode(&data, None).unwrap().as_ref() == &[0x42; 0x8000][..]);
+    }
+
+    #[test]
+    fn zip_entries() {
+        let data = zip(&[
+            ("readme.txt", b"hello"),
+            ("roms/first.GB", b"first"),
+            ("second.gbc", b"second"),
+        ]);
+        assert!(decode(&data, None).unwrap().as_ref() == b"first");
+        assert!(decode(&data, Some("second.gbc")).unwrap().as_ref() == b"second");
+        let missing = decode(&data, Some("third.gb")).unwrap_err();
+        assert!(missing.kind() == ErrorKind::NotFound);
+        let no_rom = decode(&zip(&[("readme.txt", b"hello")]), None).unwrap_err();
+        assert!(no_rom.kind() == ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn malformed_entries() {
+        // Damaged compressed data of the entry, after the 30 byte local header
+        let mut data = zip(&[("game.gb", &[0x42; 0x8000])]);
+        let start = 30 + "game.gb".len();
+        for byte in &mut data[start + 4..start + 12] {
+            *byte ^= 0x5A;
+        }
+        assert!(decode(&data, None).is_err());
+
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&[0x42; 0x8000]).unwrap();
+        let data = encoder.finish().unwrap();
+        assert!(decode(&data[..data.len() / 2], None).is_err());
+    }
+}
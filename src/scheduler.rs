@@ -0,0 +1,132 @@
+//! Central timing of the components that run alongside the cpu. Only the
+//! ppu and the timer have events. HBlank HDMA runs from the ppu event, as
+//! its blocks are copied on the HBlank mode changes. The APU frame
+//! sequencer, serial transfers, OAM DMA and the MBC3 RTC are not emulated
+//! yet, each gets an `Event` once it exists.
+
+use crate::state::{Savestate, StateReader, StateWriter};
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use std::io;
+
+/// Components driven by the scheduler. They are only advanced when their
+/// next event is due or when the cpu accesses them, until then the cycles
+/// they are behind are counted from their last sync.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Event {
+    Ppu = 0,
+    Timer = 1,
+}
+
+const EVENTS: [Event; 2] = [Event::Ppu, Event::Timer];
+const NEVER: u64 = u64::MAX;
+
+/// Timestamps in cpu cycles of the next event of every component
+pub struct Scheduler {
+    now: u64,
+    events: [u64; EVENTS.len()], /* when each event is due, NEVER if not scheduled */
+    synced: [u64; EVENTS.len()], /* when each component was last advanced */
+    next: u64,                   /* earliest of `events` */
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Self {
+            now: 0,
+            events: [NEVER; EVENTS.len()],
+            synced: [0; EVENTS.len()],
+            next: NEVER,
+        }
+    }
+}
+
+impl Savestate for Scheduler {
+    fn save_state(&self, out: &mut StateWriter) {
+        out.u64(self.now);
+        for (&at, &synced) in self.events.iter().zip(self.synced.iter()) {
+            out.u64(at);
+            out.u64(synced);
+        }
+    }
+
+    fn load_state(&mut self, input: &mut StateReader) -> io::Result<()> {
+        self.now = input.u64()?;
+        for i in 0..EVENTS.len() {
+            self.events[i] = input.u64()?;
+            self.synced[i] = input.u64()?;
+        }
+        self.update_next();
+        Ok(())
+    }
+}
+
+impl Scheduler {
+    /// Moves the clock forward, returns if an event became due
+    #[inline(always)]
+    pub fn advance(&mut self, cycles: u32) -> bool {
+        self.now += cycles as u64;
+        self.now >= self.next
+    }
+
+    /// Schedules the event the given cycles from now, replacing the
+    /// previous one
+    pub fn schedule_in(&mut self, event: Event, cycles: u64) {
+        self.events[event as usize] = self.now + cycles;
+        self.update_next();
+    }
+
+    pub fn cancel(&mut self, event: Event) {
+        self.events[event as usize] = NEVER;
+        self.update_next();
+    }
+
+    /// Removes and returns the earliest event that is due
+    pub fn pop_due(&mut self) -> Option<Event> {
+        if self.now < self.next {
+            return None;
+        }
+        let event = EVENTS
+            .iter()
+            .copied()
+            .min_by_key(|&event| self.events[event as usize])?;
+        self.cancel(event);
+        Some(event)
+    }
+
+    /// Cycles since the component was last advanced, marks it as up to date
+    pub fn sync(&mut self, event: Event) -> u64 {
+        let elapsed = self.now - self.synced[event as usize];
+        self.synced[event as usize] = self.now;
+        elapsed
+    }
+
+    fn update_next(&mut self) {
+        self.next = self.events.iter().copied().min().unwrap_or(NEVER);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn events_in_order() {
+        let mut scheduler = Scheduler::default();
+        scheduler.schedule_in(Event::Timer, 16);
+        scheduler.schedule_in(Event::Ppu, 8);
+        assert!(!scheduler.advance(4));
+        assert!(scheduler.pop_due().is_none());
+        assert!(scheduler.advance(16));
+        assert!(scheduler.pop_due() == Some(Event::Ppu));
+        assert!(scheduler.pop_due() == Some(Event::Timer));
+        assert!(scheduler.pop_due().is_none());
+        assert!(scheduler.sync(Event::Ppu) == 20);
+        assert!(scheduler.sync(Event::Ppu) == 0);
+        // Rescheduling replaces the pending event
+        scheduler.schedule_in(Event::Timer, 4);
+        scheduler.schedule_in(Event::Timer, 100);
+        assert!(!scheduler.advance(4));
+        scheduler.cancel(Event::Timer);
+        assert!(!scheduler.advance(1000));
+    }
+}
@@ -0,0 +1,58 @@
+use crate::video::{SCREEN_HEIGHT, SCREEN_WIDTH};
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use std::{
+    fs::File,
+    io::{self, BufWriter, Error},
+    path::Path,
+};
+
+/// Expands RGB555 pixels to 8 bits per channel, the low bits repeat the
+/// high ones so white stays 0xFF
+pub fn rgb8(framebuffer: &[u16]) -> Vec<u8> {
+    let expand = |c: u16| ((c << 3) | (c >> 2)) as u8;
+    framebuffer
+        .iter()
+        .flat_map(|pixel| {
+            let r = pixel & 0x1F;
+            let g = (pixel >> 5) & 0x1F;
+            let b = (pixel >> 10) & 0x1F;
+            vec![expand(r), expand(g), expand(b)]
+        })
+        .collect()
+}
+
+/// Writes a screen sized framebuffer to a PNG file
+pub fn write_png(path: &Path, framebuffer: &[u16]) -> io::Result<()> {
+    let file = File::create(path)?;
+    let mut encoder = png::Encoder::new(
+        BufWriter::new(file),
+        SCREEN_WIDTH as u32,
+        SCREEN_HEIGHT as u32,
+    );
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header().map_err(Error::other)?;
+    writer
+        .write_image_data(&rgb8(framebuffer))
+        .map_err(Error::other)?;
+    info!("Saved screenshot to {}", path.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rgb555_expansion() {
+        assert!(
+            rgb8(&[0x7FFF, 0x0000, 0x001F, 0x03E0, 0x7C00])
+                == vec![
+                    0xFF, 0xFF, 0xFF, 0x00, 0x00, 0x00, 0xFF, 0x00, 0x00, 0x00, 0xFF, 0x00, 0x00,
+                    0x00, 0xFF
+                ]
+        );
+        assert!(rgb8(&[0x294A]) == vec![0x52, 0x52, 0x52]);
+    }
+}
@@ -0,0 +1,238 @@
+use crate::cpu::Cpu;
+use crate::mem::CartHeader;
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use std::{
+    fs,
+    io::{self, Error, ErrorKind},
+    path::{Path, PathBuf},
+};
+
+const MAGIC: &[u8; 8] = b"UBOYSTAT";
+/* Needs to be bumped whenever the layout of any saved component changes */
+pub const STATE_VERSION: u16 = 4;
+
+/// Components that can be written to and restored from a save state.
+/// Fields are written in a fixed order, so `load_state` has to read them
+/// back in the same order `save_state` wrote them.
+pub trait Savestate {
+    fn save_state(&self, out: &mut StateWriter);
+    fn load_state(&mut self, input: &mut StateReader) -> io::Result<()>;
+}
+
+#[derive(Default)]
+pub struct StateWriter {
+    buf: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn u8(&mut self, val: u8) {
+        self.buf.push(val);
+    }
+    pub fn bool(&mut self, val: bool) {
+        self.buf.push(val as u8);
+    }
+    pub fn u16(&mut self, val: u16) {
+        self.buf.extend_from_slice(&val.to_le_bytes());
+    }
+    pub fn u32(&mut self, val: u32) {
+        self.buf.extend_from_slice(&val.to_le_bytes());
+    }
+    pub fn u64(&mut self, val: u64) {
+        self.buf.extend_from_slice(&val.to_le_bytes());
+    }
+    pub fn bytes(&mut self, val: &[u8]) {
+        self.buf.extend_from_slice(val);
+    }
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        let bytes = self
+            .data
+            .get(self.pos..self.pos.saturating_add(len))
+            .ok_or_else(|| Error::new(ErrorKind::UnexpectedEof, "Save state is truncated"))?;
+        self.pos += len;
+        Ok(bytes)
+    }
+    pub fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+    pub fn bool(&mut self) -> io::Result<bool> {
+        Ok(self.u8()? != 0)
+    }
+    pub fn u16(&mut self) -> io::Result<u16> {
+        let mut bytes = [0; 2];
+        bytes.copy_from_slice(self.take(2)?);
+        Ok(u16::from_le_bytes(bytes))
+    }
+    pub fn u32(&mut self) -> io::Result<u32> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(bytes))
+    }
+    pub fn u64(&mut self) -> io::Result<u64> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+    /// Fills `buf` completely from the state
+    pub fn bytes(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        buf.copy_from_slice(self.take(buf.len())?);
+        Ok(())
+    }
+    /// Reads `len` bytes, only allocating once they are known to be there
+    pub fn vec(&mut self, len: usize) -> io::Result<Vec<u8>> {
+        Ok(self.take(len)?.to_vec())
+    }
+}
+
+/// Path of a numbered save slot, kept next to the rom as `<rom>.ss<slot>`
+pub fn slot_path(rom: &str, slot: u8) -> PathBuf {
+    Path::new(rom).with_extension(format!("ss{}", slot))
+}
+
+fn write_header(out: &mut StateWriter, cart: &CartHeader) {
+    out.bytes(MAGIC);
+    out.u16(STATE_VERSION);
+    out.u8(cart.title.len() as u8);
+    out.bytes(cart.title.as_bytes());
+    out.u8(cart.checksum);
+    out.u16(cart.global_checksum);
+}
+
+/// Checks the state was saved by a compatible version for the same rom
+fn check_header(input: &mut StateReader, cart: &CartHeader) -> io::Result<()> {
+    let mut magic = [0; 8];
+    input.bytes(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(Error::new(ErrorKind::InvalidData, "Not a save state file"));
+    }
+    let version = input.u16()?;
+    if version != STATE_VERSION {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "Save state version {} is not supported, expected {}",
+                version, STATE_VERSION
+            ),
+        ));
+    }
+    let mut title = vec![0; input.u8()? as usize];
+    input.bytes(&mut title)?;
+    let checksum = input.u8()?;
+    let global_checksum = input.u16()?;
+    if title != cart.title.as_bytes()
+        || checksum != cart.checksum
+        || global_checksum != cart.global_checksum
+    {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "Save state belongs to '{}', not to the loaded rom",
+                String::from_utf8_lossy(&title)
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Serializes the whole machine, including the header identifying the rom
+pub fn save_bytes(cpu: &Cpu, cart: &CartHeader) -> Vec<u8> {
+    let mut out = StateWriter::default();
+    write_header(&mut out, cart);
+    cpu.save_state(&mut out);
+    out.into_bytes()
+}
+
+/// Restores the machine from `save_bytes` output, refusing states of other roms
+pub fn load_bytes(cpu: &mut Cpu, cart: &CartHeader, data: &[u8]) -> io::Result<()> {
+    let mut input = StateReader::new(data);
+    check_header(&mut input, cart)?;
+    cpu.load_state(&mut input)
+}
+
+/// State of the machine without the header, for snapshots kept in memory
+pub fn snapshot(cpu: &Cpu) -> Vec<u8> {
+    let mut out = StateWriter::default();
+    cpu.save_state(&mut out);
+    out.into_bytes()
+}
+
+pub fn restore(cpu: &mut Cpu, data: &[u8]) -> io::Result<()> {
+    cpu.load_state(&mut StateReader::new(data))
+}
+
+pub fn save(cpu: &Cpu, cart: &CartHeader, path: &Path) -> io::Result<()> {
+    fs::write(path, save_bytes(cpu, cart))?;
+    info!("Saved state to {}", path.display());
+    Ok(())
+}
+
+pub fn load(cpu: &mut Cpu, cart: &CartHeader, path: &Path) -> io::Result<()> {
+    let data = fs::read(path)?;
+    load_bytes(cpu, cart, &data)?;
+    info!("Loaded state from {}", path.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem::Memory;
+
+    fn cart_with_title(title: &[u8]) -> CartHeader {
+        let mut rom = vec![0; 0x8000];
+        rom[0x134..0x134 + title.len()].copy_from_slice(title);
+        CartHeader::new(&rom).unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        let cart = cart_with_title(b"TEST");
+        let mut mem = Memory::default();
+        mem.set_cgb(true);
+        mem.write(0xFF70, 3);
+        mem.write(0xD123, 0x42);
+        mem.write(0xFF4F, 1);
+        mem.write(0x9800, 0x24);
+        mem.write(0xFF80, 0x99);
+        mem.tick(1000);
+        let cpu = Cpu::new(mem);
+        let data = save_bytes(&cpu, &cart);
+
+        let mut restored = Cpu::new(Memory::default());
+        load_bytes(&mut restored, &cart, &data).unwrap();
+        assert!(save_bytes(&restored, &cart) == data);
+    }
+
+    #[test]
+    fn refuses_other_rom() {
+        let cpu = Cpu::new(Memory::default());
+        let data = save_bytes(&cpu, &cart_with_title(b"TEST"));
+        let mut other = Cpu::new(Memory::default());
+        let err = load_bytes(&mut other, &cart_with_title(b"OTHER"), &data).unwrap_err();
+        assert!(err.kind() == ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn refuses_truncated_state() {
+        let cart = cart_with_title(b"TEST");
+        let cpu = Cpu::new(Memory::default());
+        let data = save_bytes(&cpu, &cart);
+        let mut other = Cpu::new(Memory::default());
+        let err = load_bytes(&mut other, &cart, &data[..data.len() - 1]).unwrap_err();
+        assert!(err.kind() == ErrorKind::UnexpectedEof);
+    }
+}
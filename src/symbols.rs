@@ -0,0 +1,118 @@
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use std::{collections::BTreeMap, fs, io, path::Path};
+
+/// Labels from RGBDS or no$gmb `.sym` files, one `bank:address label`
+/// entry per line, `;` starts a comment
+#[derive(Default)]
+pub struct Symbols {
+    labels: BTreeMap<(u16, u16), String>, /* keyed by bank and address */
+}
+
+impl Symbols {
+    pub fn parse(text: &str) -> Self {
+        let mut labels = BTreeMap::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split(';').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let entry = line
+                .split_once(char::is_whitespace)
+                .and_then(|(location, label)| {
+                    let (bank, addr) = location.split_once(':')?;
+                    let bank = u16::from_str_radix(bank, 16).ok()?;
+                    let addr = u16::from_str_radix(addr, 16).ok()?;
+                    Some(((bank, addr), label.trim().to_string()))
+                });
+            match entry {
+                Some((location, label)) => {
+                    labels.insert(location, label);
+                }
+                None => warn!("Ignoring symbol file line {}: {}", number + 1, line),
+            }
+        }
+        Self { labels }
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self> {
+        Ok(Self::parse(&fs::read_to_string(path)?))
+    }
+
+    pub fn len(&self) -> usize {
+        self.labels.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.labels.is_empty()
+    }
+
+    pub fn label(&self, bank: u16, addr: u16) -> Option<&str> {
+        self.labels.get(&(bank, addr)).map(String::as_str)
+    }
+
+    /// Bank and address of a label
+    pub fn find(&self, name: &str) -> Option<(u16, u16)> {
+        self.labels
+            .iter()
+            .find(|(_, label)| *label == name)
+            .map(|(location, _)| *location)
+    }
+
+    /// Closest label at or before the address in the same memory area,
+    /// with the offset from it
+    pub fn nearest(&self, bank: u16, addr: u16) -> Option<(&str, u16)> {
+        let start = area_start(addr)?;
+        self.labels
+            .range((bank, start)..=(bank, addr))
+            .next_back()
+            .map(|((_, label_addr), label)| (label.as_str(), addr - label_addr))
+    }
+
+    /// The address followed by the closest label, like `0153 <Main+3>`
+    pub fn describe(&self, bank: u16, addr: u16) -> String {
+        match self.nearest(bank, addr) {
+            Some((label, 0)) => format!("{:04X} <{}>", addr, label),
+            Some((label, offset)) => format!("{:04X} <{}+{}>", addr, label, offset),
+            None => format!("{:04X}", addr),
+        }
+    }
+}
+
+/// First address of the memory area the address is in, labels of other
+/// areas are never used for it
+fn area_start(addr: u16) -> Option<u16> {
+    match addr {
+        0x0000..=0x3FFF => Some(0x0000),
+        0x4000..=0x7FFF => Some(0x4000),
+        0x8000..=0x9FFF => Some(0x8000),
+        0xA000..=0xBFFF => Some(0xA000),
+        0xC000..=0xCFFF => Some(0xC000),
+        0xD000..=0xDFFF => Some(0xD000),
+        0xFE00..=0xFE9F => Some(0xFE00),
+        0xFF80..=0xFFFE => Some(0xFF80),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_and_lookup() {
+        let symbols = Symbols::parse(
+            "; File generated by rgblink\n00:0150 Main\n00:0160 Main.loop\n01:4000 Banked ; far\nbogus\n00:C000 wBuffer\n",
+        );
+        assert!(symbols.len() == 4);
+        assert!(symbols.label(0, 0x0150) == Some("Main"));
+        assert!(symbols.label(1, 0x0150).is_none());
+        assert!(symbols.find("Banked") == Some((1, 0x4000)));
+        assert!(symbols.describe(0, 0x0163) == "0163 <Main.loop+3>");
+        assert!(symbols.describe(0, 0x0150) == "0150 <Main>");
+        assert!(symbols.describe(1, 0x4010) == "4010 <Banked+16>");
+        // Rom labels do not leak into other areas
+        assert!(symbols.describe(0, 0x4000) == "4000");
+        assert!(symbols.describe(0, 0xC004) == "C004 <wBuffer+4>");
+    }
+}
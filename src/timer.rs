@@ -0,0 +1,158 @@
+use crate::state::{Savestate, StateReader, StateWriter};
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use std::io;
+
+/* Bit of the IF register requested by the timer */
+pub const INT_TIMER: u8 = 1 << 2;
+
+/// DIV and TIMA, clocked by the cpu so they run twice as fast in double speed
+#[derive(Default)]
+pub struct Timer {
+    counter: u16, /* DIV at 0xFF04 is the upper byte */
+    tima: u8,     /* Timer counter at 0xFF05 */
+    tma: u8,      /* Timer modulo at 0xFF06 */
+    tac: u8,      /* Timer control at 0xFF07 */
+}
+
+impl Timer {
+    pub fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0xFF04 => (self.counter >> 8) as u8,
+            0xFF05 => self.tima,
+            0xFF06 => self.tma,
+            0xFF07 => 0xF8 | self.tac,
+            _ => {
+                warn!("Reading 0x{:X} which is not a timer register", addr);
+                0xFF
+            }
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, val: u8) {
+        match addr {
+            0xFF04 => self.reset_div(),
+            0xFF05 => self.tima = val,
+            0xFF06 => self.tma = val,
+            0xFF07 => self.tac = val & 0x7,
+            _ => warn!("Writing 0x{:X} which is not a timer register", addr),
+        }
+    }
+
+    pub fn reset_div(&mut self) {
+        self.counter = 0;
+    }
+
+    /// Bit of the internal counter whose falling edge increments TIMA
+    fn tima_bit(&self) -> u16 {
+        match self.tac & 0x3 {
+            0 => 1 << 9,
+            1 => 1 << 3,
+            2 => 1 << 5,
+            _ => 1 << 7,
+        }
+    }
+
+    /// Cycles between two increments of TIMA
+    fn period(&self) -> u16 {
+        self.tima_bit() * 2
+    }
+
+    /// Advances the timer by the given cpu cycles, returns the interrupts it requests
+    pub fn step(&mut self, cycles: u64) -> u8 {
+        let period = self.period() as u64;
+        let start = self.counter as u64;
+        let end = start + cycles;
+        self.counter = end as u16;
+        if self.tac & 0x4 == 0 {
+            return 0;
+        }
+        // TIMA increments on every falling edge of the selected bit, when
+        // the counter passes a multiple of the period
+        let mut edges = end / period - start / period;
+        let mut irq = 0;
+        while edges > 0 {
+            let to_overflow = 0x100 - self.tima as u64;
+            if edges < to_overflow {
+                self.tima += edges as u8;
+                break;
+            }
+            edges -= to_overflow;
+            self.tima = self.tma;
+            irq |= INT_TIMER;
+        }
+        irq
+    }
+
+    /// Cpu cycles until TIMA overflows and requests an interrupt, `None`
+    /// while it is stopped
+    pub fn next_event(&self) -> Option<u64> {
+        if self.tac & 0x4 == 0 {
+            return None;
+        }
+        let period = self.period() as u64;
+        let to_edge = period - self.counter as u64 % period;
+        Some(to_edge + (0xFF - self.tima as u64) * period)
+    }
+}
+
+impl Savestate for Timer {
+    fn save_state(&self, out: &mut StateWriter) {
+        out.u16(self.counter);
+        out.u8(self.tima);
+        out.u8(self.tma);
+        out.u8(self.tac);
+    }
+
+    fn load_state(&mut self, input: &mut StateReader) -> io::Result<()> {
+        self.counter = input.u16()?;
+        self.tima = input.u8()?;
+        self.tma = input.u8()?;
+        self.tac = input.u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tima_overflow_reloads_tma() {
+        let mut timer = Timer::default();
+        timer.write(0xFF06, 0xAB);
+        timer.write(0xFF05, 0xFF);
+        timer.write(0xFF07, 0x5); // enabled, every 16 cycles
+        assert!(timer.step(12) == 0);
+        assert!(timer.step(4) == INT_TIMER);
+        assert!(timer.read(0xFF05) == 0xAB);
+        assert!(timer.read(0xFF07) == 0xFD);
+    }
+
+    #[test]
+    fn next_event_is_the_overflow() {
+        let mut timer = Timer::default();
+        timer.write(0xFF05, 0xFE);
+        assert!(timer.next_event().is_none());
+        timer.write(0xFF07, 0x4); // enabled, every 1024 cycles
+        timer.step(1000);
+        assert!(timer.next_event() == Some(24 + 1024));
+        assert!(timer.step(24 + 1020) == 0);
+        assert!(timer.read(0xFF05) == 0xFF);
+        assert!(timer.step(4) == INT_TIMER);
+        // Catching up many increments at once wraps through TMA
+        timer.write(0xFF06, 0xF0);
+        timer.write(0xFF05, 0xFC);
+        assert!(timer.step(1024 * 21) == INT_TIMER);
+        assert!(timer.read(0xFF05) == 0xF1);
+    }
+
+    #[test]
+    fn div_counts_and_resets() {
+        let mut timer = Timer::default();
+        timer.step(256 * 3);
+        assert!(timer.read(0xFF04) == 3);
+        timer.write(0xFF04, 0x55);
+        assert!(timer.read(0xFF04) == 0);
+    }
+}
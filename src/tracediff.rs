@@ -0,0 +1,136 @@
+use crate::cpu::{Cpu, Reg};
+use crate::disasm;
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use std::{
+    collections::VecDeque,
+    io::{self, BufRead, Write},
+    panic::{self, AssertUnwindSafe},
+};
+
+/// `NAME:VALUE` fields of a trace line
+fn fields(line: &str) -> Vec<(&str, &str)> {
+    line.split_whitespace()
+        .filter_map(|field| field.split_once(':'))
+        .collect()
+}
+
+/// Fields present in both lines with different values, as
+/// (name, expected, actual)
+fn differences<'a>(expected: &'a str, actual: &'a str) -> Vec<(&'a str, &'a str, &'a str)> {
+    let actual = fields(actual);
+    fields(expected)
+        .into_iter()
+        .filter_map(|(name, value)| {
+            let (_, got) = actual
+                .iter()
+                .find(|(field, _)| field.eq_ignore_ascii_case(name))?;
+            if value.eq_ignore_ascii_case(got) {
+                None
+            } else {
+                Some((name, value, *got))
+            }
+        })
+        .collect()
+}
+
+/// Disassembly and trace line of the instruction about to run
+fn describe(cpu: &Cpu) -> String {
+    let mem = cpu.mem();
+    let pc = cpu.reg(Reg::PC);
+    let instruction = disasm::decode(pc, |addr| mem.peek8(addr));
+    format!(
+        "{:<40}{}",
+        instruction.listing(mem.symbols(), |addr| mem.bank_at(addr)),
+        cpu.trace_line()
+    )
+}
+
+/// Runs the cpu along a reference trace until the first line that does not
+/// match its state, then prints the last `context` instructions and the
+/// differing fields. Returns the number of matching instructions and if
+/// the run diverged.
+pub fn run(
+    cpu: &mut Cpu,
+    reference: impl BufRead,
+    context: usize,
+    out: &mut impl Write,
+) -> io::Result<(u64, bool)> {
+    let mut history = VecDeque::with_capacity(context + 1);
+    let mut matched = 0;
+    for line in reference.lines() {
+        let expected = line?;
+        if expected.trim().is_empty() {
+            continue;
+        }
+        let actual = cpu.trace_line();
+        let differences = differences(&expected, &actual);
+        if !differences.is_empty() {
+            writeln!(out, "Diverged at instruction {}", matched + 1)?;
+            if !history.is_empty() {
+                writeln!(out, "Last instructions:")?;
+            }
+            for line in &history {
+                writeln!(out, "  {}", line)?;
+            }
+            writeln!(out, "Diverging instruction:")?;
+            writeln!(out, "  {}", describe(cpu))?;
+            writeln!(out, "Expected: {}", expected.trim())?;
+            writeln!(out, "Actual:   {}", actual)?;
+            for (name, expected, actual) in differences {
+                writeln!(out, "  {} expected {}, got {}", name, expected, actual)?;
+            }
+            return Ok((matched, true));
+        }
+
+        if context > 0 {
+            if history.len() == context {
+                history.pop_front();
+            }
+            history.push_back(describe(cpu));
+        }
+        if panic::catch_unwind(AssertUnwindSafe(|| cpu.step())).is_err() {
+            writeln!(out, "Emulation crashed at instruction {}", matched + 1)?;
+            for line in &history {
+                writeln!(out, "  {}", line)?;
+            }
+            return Ok((matched, true));
+        }
+        matched += 1;
+    }
+    writeln!(out, "No divergence in {} instructions", matched)?;
+    Ok((matched, false))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem::Memory;
+
+    const START: &str = "A:00 F:00 B:00 C:00 D:00 E:00 H:00 L:00 SP:0000";
+
+    #[test]
+    fn finds_first_divergence() {
+        // Every instruction of the empty rom is a NOP
+        let reference = format!(
+            "{0} PC:0000 PCMEM:00,00,00,00\n{0} PC:0001 PCMEM:00,00,00,00\n{0} PC:0003 PCMEM:00,00,00,00\n",
+            START
+        );
+        let mut cpu = Cpu::new(Memory::default());
+        let mut out = Vec::new();
+        let result = run(&mut cpu, reference.as_bytes(), 1, &mut out).unwrap();
+        assert!(result == (2, true));
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with("Diverged at instruction 3\nLast instructions:\n  0001: 00"));
+        assert!(out.ends_with("  PC expected 0003, got 0002\n"));
+    }
+
+    #[test]
+    fn matching_trace() {
+        // Fields missing from the reference are not compared
+        let reference = format!("{} pc:0000\n\n{} PC:0001\n", START, START);
+        let mut cpu = Cpu::new(Memory::default());
+        let mut out = Vec::new();
+        assert!(run(&mut cpu, reference.as_bytes(), 5, &mut out).unwrap() == (2, false));
+    }
+}
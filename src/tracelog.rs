@@ -0,0 +1,48 @@
+//! Logging of every instruction and memory access. It is compiled in with
+//! the `trace-log` feature and still needs to be switched on at runtime,
+//! otherwise the events are never formatted.
+
+#[cfg(feature = "trace-log")]
+use std::sync::atomic::{AtomicBool, Ordering};
+
+#[cfg(feature = "trace-log")]
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Whether the events are logged, always false without the feature so the
+/// checks are optimized out
+#[inline(always)]
+pub fn enabled() -> bool {
+    #[cfg(feature = "trace-log")]
+    {
+        ENABLED.load(Ordering::Relaxed)
+    }
+    #[cfg(not(feature = "trace-log"))]
+    {
+        false
+    }
+}
+
+/// Switches the events on or off, returns false when the feature is not
+/// compiled in
+pub fn set_enabled(enabled: bool) -> bool {
+    #[cfg(feature = "trace-log")]
+    {
+        ENABLED.store(enabled, Ordering::Relaxed);
+        true
+    }
+    #[cfg(not(feature = "trace-log"))]
+    {
+        let _ = enabled;
+        false
+    }
+}
+
+/// Logs an event at trace level when tracing is enabled
+macro_rules! trace_event {
+    ($($arg:tt)+) => {
+        if $crate::tracelog::enabled() {
+            log::trace!($($arg)+);
+        }
+    };
+}
+pub(crate) use trace_event;
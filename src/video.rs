@@ -1,31 +1,695 @@
-/*
-struct video {
-    struct screen_buf {
-        union {
-            uint16_t pixels [256][256];
-            struct {
-                uint16_t pixels[8][8];
-            } tiles [32][32];
+use crate::mem::Vram;
+use crate::state::{Savestate, StateReader, StateWriter};
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use std::io;
+
+pub const SCREEN_WIDTH: usize = 160;
+pub const SCREEN_HEIGHT: usize = 144;
+
+/* Lengths of the modes in a scanline, in cycles */
+const OAM_SCAN_CYCLES: u32 = 80;
+const DRAWING_CYCLES: u32 = 172;
+const LINE_CYCLES: u32 = 456;
+const VBLANK_LINE: u8 = 144;
+const LINES_PER_FRAME: u8 = 154;
+const FRAME_CYCLES: u32 = LINE_CYCLES * LINES_PER_FRAME as u32;
+
+/* RGB555 colours of the four DMG shades */
+const DMG_COLOURS: [u16; 4] = [0x7FFF, 0x56B5, 0x294A, 0x0000];
+
+/* Default palettes the CGB boot rom picks for DMG games it doesn't know */
+const COMPAT_BG_COLOURS: [u16; 4] = [0x7FFF, 0x1BEF, 0x6180, 0x0000];
+const COMPAT_OBJ_COLOURS: [u16; 4] = [0x7FFF, 0x421F, 0x1CF2, 0x0000];
+
+/* Bits of the IF register requested by the ppu */
+pub const INT_VBLANK: u8 = 1 << 0;
+pub const INT_STAT: u8 = 1 << 1;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Mode {
+    HBlank = 0,
+    VBlank = 1,
+    OamScan = 2,
+    Drawing = 3,
+}
+
+/// Attributes of a tile, either a BG map entry from VRAM bank 1 (CGB only)
+/// or the 4th byte of an OAM entry. They share the same layout except
+/// `dmg_palette` which only exists for objects.
+#[derive(Copy, Clone, Default, PartialEq, Debug)]
+pub struct TileAttr {
+    pub palette: u8,     /* bits 0-2, CGB palette number */
+    pub bank: u8,        /* bit 3, VRAM bank of the tile data */
+    pub dmg_palette: u8, /* bit 4, OBP0 or OBP1, objects only */
+    pub xflip: bool,     /* bit 5 */
+    pub yflip: bool,     /* bit 6 */
+    pub priority: bool,  /* bit 7, BG over OBJ */
+}
+
+impl From<u8> for TileAttr {
+    fn from(val: u8) -> Self {
+        Self {
+            palette: val & 0x7,
+            bank: (val >> 3) & 1,
+            dmg_palette: (val >> 4) & 1,
+            xflip: val & (1 << 5) != 0,
+            yflip: val & (1 << 6) != 0,
+            priority: val & (1 << 7) != 0,
+        }
+    }
+}
+
+/// CGB palette memory for 8 palettes of 4 RGB555 colours, accessed through
+/// an index register (BCPS/OCPS) and a data register (BCPD/OCPD)
+pub struct PaletteRam {
+    data: [u8; 64],
+    index: u8,       /* bits 0-5 of the index register */
+    auto_inc: bool,  /* bit 7 of the index register */
+}
+
+impl Default for PaletteRam {
+    fn default() -> Self {
+        let mut palettes = Self {
+            data: [0; 64],
+            index: 0,
+            auto_inc: false,
+        };
+        for palette in 0..8 {
+            palettes.set_colours(palette, &DMG_COLOURS);
+        }
+        palettes
+    }
+}
+
+impl PaletteRam {
+    pub fn read_index(&self) -> u8 {
+        0x40 | ((self.auto_inc as u8) << 7) | self.index
+    }
+    pub fn write_index(&mut self, val: u8) {
+        self.index = val & 0x3F;
+        self.auto_inc = val & 0x80 != 0;
+    }
+    pub fn read_data(&self) -> u8 {
+        self.data[self.index as usize]
+    }
+    /// Writes to the palette data, `locked` is set during mode 3 where the
+    /// write is dropped but the index still increments
+    pub fn write_data(&mut self, val: u8, locked: bool) {
+        if !locked {
+            self.data[self.index as usize] = val;
+        }
+        if self.auto_inc {
+            self.index = (self.index + 1) & 0x3F;
+        }
+    }
+    /// Fills a palette with RGB555 colours
+    pub fn set_colours(&mut self, palette: u8, colours: &[u16; 4]) {
+        for (i, colour) in colours.iter().enumerate() {
+            let j = palette as usize * 8 + i * 2;
+            self.data[j] = *colour as u8;
+            self.data[j + 1] = (*colour >> 8) as u8;
+        }
+    }
+    /// RGB555 value of a colour, stored little endian
+    pub fn colour(&self, palette: u8, index: u8) -> u16 {
+        let i = palette as usize * 8 + index as usize * 2;
+        (self.data[i] as u16 | (self.data[i + 1] as u16) << 8) & 0x7FFF
+    }
+}
+
+impl Savestate for PaletteRam {
+    fn save_state(&self, out: &mut StateWriter) {
+        out.bytes(&self.data);
+        out.u8(self.index);
+        out.bool(self.auto_inc);
+    }
+
+    fn load_state(&mut self, input: &mut StateReader) -> io::Result<()> {
+        input.bytes(&mut self.data)?;
+        self.index = input.u8()? & 0x3F;
+        self.auto_inc = input.bool()?;
+        Ok(())
+    }
+}
+
+pub struct Ppu {
+    lcdc: u8, /* LCD Control at 0xFF40 */
+    stat: u8, /* LCD Status at 0xFF41, only the interrupt select bits */
+    scy: u8,  /* Scroll Y at 0xFF42 */
+    scx: u8,  /* Scroll X at 0xFF43 */
+    ly: u8,   /* LCD Current Scanned Line at 0xFF44 */
+    lyc: u8,  /* LY Compare at 0xFF45 */
+    bgp: u8,  /* BG Palette Data at 0xFF47 */
+    /* When value of OAM palette selection flag is 0,
+     * this value is used, otherwise obp1
+     */
+    obp0: u8, /* at 0xFF48 */
+    obp1: u8, /* at 0xFF49 */
+    wy: u8,   /* Window Y coordinate at 0xFF4A */
+    wx: u8,   /* Window X coordinate at 0xFF4B */
+    bg_palettes: PaletteRam,  /* BCPS/BCPD at 0xFF68 - 0xFF69 */
+    obj_palettes: PaletteRam, /* OCPS/OCPD at 0xFF6A - 0xFF6B */
+
+    mode: Mode,
+    clock: u32,       /* cycles spent in the current line */
+    window_line: u8,  /* internal line counter of the window */
+    stat_line: bool,  /* STAT interrupt is requested on the rising edge */
+    hblanks: u32,     /* HBlanks entered since the last `take_hblanks` */
+    cgb: bool,
+    compat: bool, /* DMG game running on CGB hardware */
+    framebuffer: Vec<u16>,
+    frame: u64,
+}
+
+impl Default for Ppu {
+    fn default() -> Self {
+        Self {
+            lcdc: 0x91,
+            stat: 0,
+            scy: 0,
+            scx: 0,
+            ly: 0,
+            lyc: 0,
+            bgp: 0xFC,
+            obp0: 0xFF,
+            obp1: 0xFF,
+            wy: 0,
+            wx: 0,
+            bg_palettes: PaletteRam::default(),
+            obj_palettes: PaletteRam::default(),
+            mode: Mode::OamScan,
+            clock: 0,
+            window_line: 0,
+            stat_line: false,
+            hblanks: 0,
+            cgb: false,
+            compat: false,
+            framebuffer: vec![0; SCREEN_WIDTH * SCREEN_HEIGHT],
+            frame: 0,
+        }
+    }
+}
+
+impl Savestate for Ppu {
+    fn save_state(&self, out: &mut StateWriter) {
+        for reg in &[
+            self.lcdc, self.stat, self.scy, self.scx, self.ly, self.lyc, self.bgp, self.obp0,
+            self.obp1, self.wy, self.wx,
+        ] {
+            out.u8(*reg);
+        }
+        self.bg_palettes.save_state(out);
+        self.obj_palettes.save_state(out);
+        out.u8(self.mode as u8);
+        out.u32(self.clock);
+        out.u8(self.window_line);
+        out.bool(self.stat_line);
+        out.u32(self.hblanks);
+        out.bool(self.cgb);
+        out.bool(self.compat);
+        for pixel in &self.framebuffer {
+            out.u16(*pixel);
+        }
+        out.u64(self.frame);
+    }
+
+    fn load_state(&mut self, input: &mut StateReader) -> io::Result<()> {
+        for reg in [
+            &mut self.lcdc,
+            &mut self.stat,
+            &mut self.scy,
+            &mut self.scx,
+            &mut self.ly,
+            &mut self.lyc,
+            &mut self.bgp,
+            &mut self.obp0,
+            &mut self.obp1,
+            &mut self.wy,
+            &mut self.wx,
+        ]
+        .iter_mut()
+        {
+            **reg = input.u8()?;
+        }
+        self.bg_palettes.load_state(input)?;
+        self.obj_palettes.load_state(input)?;
+        self.mode = match input.u8()? & 0x3 {
+            0 => Mode::HBlank,
+            1 => Mode::VBlank,
+            2 => Mode::OamScan,
+            _ => Mode::Drawing,
+        };
+        self.clock = input.u32()?;
+        self.window_line = input.u8()?;
+        self.stat_line = input.bool()?;
+        self.hblanks = input.u32()?;
+        self.cgb = input.bool()?;
+        self.compat = input.bool()?;
+        for pixel in self.framebuffer.iter_mut() {
+            *pixel = input.u16()?;
+        }
+        self.frame = input.u64()?;
+        Ok(())
+    }
+}
+
+#[allow(dead_code)]
+impl Ppu {
+    pub fn set_cgb(&mut self, cgb: bool) {
+        self.cgb = cgb;
+    }
+
+    /// DMG compatibility mode, the shades are coloured by the CGB palettes
+    /// which are seeded like the CGB boot rom does for an unknown game
+    pub fn set_compat(&mut self, compat: bool) {
+        self.compat = compat;
+        if compat {
+            self.bg_palettes.set_colours(0, &COMPAT_BG_COLOURS);
+            self.obj_palettes.set_colours(0, &COMPAT_OBJ_COLOURS);
+            self.obj_palettes.set_colours(1, &COMPAT_OBJ_COLOURS);
+        }
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    /// RGB555 colours of the last rendered frame
+    pub fn framebuffer(&self) -> &[u16] {
+        &self.framebuffer
+    }
+
+    /// Number of frames completed since power on
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0xFF40 => self.lcdc,
+            0xFF41 => {
+                let coincidence = if self.ly == self.lyc { 1 << 2 } else { 0 };
+                0x80 | self.stat | coincidence | self.mode as u8
+            }
+            0xFF42 => self.scy,
+            0xFF43 => self.scx,
+            0xFF44 => self.ly,
+            0xFF45 => self.lyc,
+            0xFF47 => self.bgp,
+            0xFF48 => self.obp0,
+            0xFF49 => self.obp1,
+            0xFF4A => self.wy,
+            0xFF4B => self.wx,
+            0xFF68 if self.cgb => self.bg_palettes.read_index(),
+            0xFF69 if self.cgb && !self.palettes_locked() => self.bg_palettes.read_data(),
+            0xFF6A if self.cgb => self.obj_palettes.read_index(),
+            0xFF6B if self.cgb && !self.palettes_locked() => self.obj_palettes.read_data(),
+            0xFF68..=0xFF6B => 0xFF,
+            _ => {
+                warn!("Reading 0x{:X} which is not a ppu register", addr);
+                0xFF
+            }
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, val: u8) {
+        match addr {
+            0xFF40 => {
+                let was_on = self.lcdc & 0x80 != 0;
+                self.lcdc = val;
+                if was_on && val & 0x80 == 0 {
+                    self.ly = 0;
+                    self.clock = 0;
+                    self.mode = Mode::HBlank;
+                    self.window_line = 0;
+                } else if !was_on && val & 0x80 != 0 {
+                    self.clock = 0;
+                    self.mode = Mode::OamScan;
+                }
+            }
+            0xFF41 => self.stat = val & 0x78,
+            0xFF42 => self.scy = val,
+            0xFF43 => self.scx = val,
+            0xFF44 => {
+                info!("Ignoring write to read only LY register");
+            }
+            0xFF45 => self.lyc = val,
+            0xFF47 => self.bgp = val,
+            0xFF48 => self.obp0 = val,
+            0xFF49 => self.obp1 = val,
+            0xFF4A => self.wy = val,
+            0xFF4B => self.wx = val,
+            0xFF68..=0xFF6B if !self.cgb => {
+                info!("Ignoring palette write outside of CGB mode");
+            }
+            0xFF68 => self.bg_palettes.write_index(val),
+            0xFF69 => {
+                let locked = self.palettes_locked();
+                self.bg_palettes.write_data(val, locked);
+            }
+            0xFF6A => self.obj_palettes.write_index(val),
+            0xFF6B => {
+                let locked = self.palettes_locked();
+                self.obj_palettes.write_data(val, locked);
+            }
+            _ => warn!("Writing 0x{:X} which is not a ppu register", addr),
+        }
+    }
+
+    /// Advances the ppu by the given cycles, returns the interrupts it requests
+    pub fn step(&mut self, cycles: u32, vram: &Vram, oam: &[u8]) -> u8 {
+        if self.lcdc & 0x80 == 0 {
+            // Frames keep being counted while the LCD is off, so the
+            // emulation can still be run frame by frame
+            self.clock += cycles;
+            while self.clock >= FRAME_CYCLES {
+                self.clock -= FRAME_CYCLES;
+                self.frame += 1;
+            }
+            return 0;
+        }
+        let mut irq = 0;
+        self.clock += cycles;
+        loop {
+            match self.mode {
+                Mode::OamScan if self.clock >= OAM_SCAN_CYCLES => {
+                    self.mode = Mode::Drawing;
+                }
+                Mode::Drawing if self.clock >= OAM_SCAN_CYCLES + DRAWING_CYCLES => {
+                    self.render_line(vram, oam);
+                    self.mode = Mode::HBlank;
+                    self.hblanks += 1;
+                }
+                Mode::HBlank if self.clock >= LINE_CYCLES => {
+                    self.clock -= LINE_CYCLES;
+                    self.ly += 1;
+                    if self.ly == VBLANK_LINE {
+                        self.mode = Mode::VBlank;
+                        self.frame += 1;
+                        irq |= INT_VBLANK;
+                    } else {
+                        self.mode = Mode::OamScan;
+                    }
+                }
+                Mode::VBlank if self.clock >= LINE_CYCLES => {
+                    self.clock -= LINE_CYCLES;
+                    self.ly += 1;
+                    if self.ly == LINES_PER_FRAME {
+                        self.ly = 0;
+                        self.window_line = 0;
+                        self.mode = Mode::OamScan;
+                    }
+                }
+                _ => break,
+            }
+            irq |= self.update_stat_line();
+        }
+        irq
+    }
+
+    /// Ppu cycles until the next mode change, or the end of the frame while
+    /// the LCD is off
+    pub fn next_event(&self) -> u32 {
+        if self.lcdc & 0x80 == 0 {
+            return FRAME_CYCLES - self.clock;
+        }
+        match self.mode {
+            Mode::OamScan => OAM_SCAN_CYCLES - self.clock,
+            Mode::Drawing => OAM_SCAN_CYCLES + DRAWING_CYCLES - self.clock,
+            Mode::HBlank | Mode::VBlank => LINE_CYCLES - self.clock,
+        }
+    }
+
+    /// Number of HBlank periods started since the last call, used by HDMA
+    pub fn take_hblanks(&mut self) -> u32 {
+        std::mem::take(&mut self.hblanks)
+    }
+
+    /// Palette data can't be accessed while the ppu is drawing
+    fn palettes_locked(&self) -> bool {
+        self.mode == Mode::Drawing
+    }
+
+    fn bg_colour(&self, palette: u8, index: u8) -> u16 {
+        if self.cgb {
+            return self.bg_palettes.colour(palette, index);
+        }
+        let shade = (self.bgp >> (index * 2)) & 0x3;
+        if self.compat {
+            self.bg_palettes.colour(0, shade)
+        } else {
+            DMG_COLOURS[shade as usize]
+        }
+    }
+
+    fn obj_colour(&self, attr: TileAttr, index: u8) -> u16 {
+        if self.cgb {
+            return self.obj_palettes.colour(attr.palette, index);
+        }
+        let obp = if attr.dmg_palette == 0 {
+            self.obp0
+        } else {
+            self.obp1
+        };
+        let shade = (obp >> (index * 2)) & 0x3;
+        if self.compat {
+            self.obj_palettes.colour(attr.dmg_palette, shade)
+        } else {
+            DMG_COLOURS[shade as usize]
+        }
+    }
+
+    fn update_stat_line(&mut self) -> u8 {
+        let line = match self.mode {
+            Mode::HBlank => self.stat & (1 << 3) != 0,
+            Mode::VBlank => self.stat & (1 << 4) != 0,
+            Mode::OamScan => self.stat & (1 << 5) != 0,
+            Mode::Drawing => false,
+        } || (self.ly == self.lyc && self.stat & (1 << 6) != 0);
+        let rising = line && !self.stat_line;
+        self.stat_line = line;
+        if rising {
+            INT_STAT
+        } else {
+            0
+        }
+    }
+
+    /// Address of a tile's data in VRAM, depending on the addressing mode of LCDC
+    fn tile_addr(&self, tile: u8) -> u16 {
+        if self.lcdc & (1 << 4) != 0 {
+            tile as u16 * 16
+        } else {
+            (0x1000 + (tile as i8 as i32) * 16) as u16
+        }
+    }
+
+    /// Colour index of a pixel in a tile row, `col` is counted from the left
+    fn tile_pixel(vram: &Vram, bank: u8, addr: u16, col: u8) -> u8 {
+        let lo = vram.read_bank(bank, addr);
+        let hi = vram.read_bank(bank, addr + 1);
+        let bit = 7 - col;
+        (((hi >> bit) & 1) << 1) | ((lo >> bit) & 1)
+    }
+
+    fn bg_pixel(&self, vram: &Vram, map: u16, x: u8, y: u8) -> (u8, TileAttr) {
+        let entry = map + (y as u16 / 8) * 32 + x as u16 / 8;
+        let tile = vram.read_bank(0, entry);
+        let attr = if self.cgb {
+            TileAttr::from(vram.read_bank(1, entry))
+        } else {
+            TileAttr::default()
         };
-    } screen_buf;
-
-    struct registers {
-        uint8_t lcdc; /* LCD Control at 0xFF40 */
-        uint8_t stat; /* LCD Status at 0xFF41 */
-        uint8_t scy; /* Scroll Y at 0xFF42 */
-        uint8_t scx; /* Scroll X at 0xFF43 */
-        uint8_t ly; /* LCD Current Scanned Line at 0xFF44 */
-        uint8_t lyc; /* LY Compare at 0xFF45 */
-        uint8_t bgp; /* BG Palette Data at 0xFF47 */
-        /* When value of OAM palette selection flag is 0, 
-         * this value is used, otherwise obp1
-         */
-        uint8_t obp0; /* at 0xFF48 */
-        uint8_t obp1; /* at 0xFF49 */
-        uint8_t wy; /* Window Y coordinate at 0xFF4A */
-        uint8_t wx; /* Window X coordinate at 0xFF4B */
-    } reg;
-
-
-} video;
- */
+        let row = if attr.yflip { 7 - y % 8 } else { y % 8 };
+        let col = if attr.xflip { 7 - x % 8 } else { x % 8 };
+        let addr = self.tile_addr(tile) + row as u16 * 2;
+        (Self::tile_pixel(vram, attr.bank, addr, col), attr)
+    }
+
+    fn render_line(&mut self, vram: &Vram, oam: &[u8]) {
+        let ly = self.ly;
+        let mut bg_index = [0u8; SCREEN_WIDTH];
+        let mut bg_priority = [false; SCREEN_WIDTH];
+        let mut line = [DMG_COLOURS[0]; SCREEN_WIDTH];
+
+        /* In CGB mode LCDC bit 0 only takes the priority away from BG and window */
+        let bg_enabled = self.cgb || self.lcdc & 1 != 0;
+        let master_priority = !self.cgb || self.lcdc & 1 != 0;
+        let window_visible =
+            bg_enabled && self.lcdc & (1 << 5) != 0 && ly >= self.wy && self.wx <= 166;
+        let mut window_drawn = false;
+
+        if bg_enabled {
+            for x in 0..SCREEN_WIDTH {
+                let (index, attr) = if window_visible && x + 7 >= self.wx as usize {
+                    window_drawn = true;
+                    let map = if self.lcdc & (1 << 6) != 0 { 0x1C00 } else { 0x1800 };
+                    let wx = (x + 7 - self.wx as usize) as u8;
+                    self.bg_pixel(vram, map, wx, self.window_line)
+                } else {
+                    let map = if self.lcdc & (1 << 3) != 0 { 0x1C00 } else { 0x1800 };
+                    let px = (x as u8).wrapping_add(self.scx);
+                    let py = ly.wrapping_add(self.scy);
+                    self.bg_pixel(vram, map, px, py)
+                };
+                bg_index[x] = index;
+                bg_priority[x] = attr.priority;
+                line[x] = self.bg_colour(attr.palette, index);
+            }
+        }
+        if window_drawn {
+            self.window_line += 1;
+        }
+
+        if self.lcdc & (1 << 1) != 0 {
+            let height = if self.lcdc & (1 << 2) != 0 { 16 } else { 8 };
+            /* At most 10 objects per line, picked in OAM order */
+            let mut objects: Vec<usize> = (0..40)
+                .filter(|i| {
+                    let y = oam[i * 4] as i16 - 16;
+                    (ly as i16) >= y && (ly as i16) < y + height
+                })
+                .take(10)
+                .collect();
+            /* On DMG the object with the smaller X coordinate wins */
+            if !self.cgb {
+                objects.sort_by_key(|i| oam[i * 4 + 1]);
+            }
+            for (x, pixel) in line.iter_mut().enumerate() {
+                for &i in objects.iter() {
+                    let ox = oam[i * 4 + 1] as i16 - 8;
+                    if (x as i16) < ox || (x as i16) >= ox + 8 {
+                        continue;
+                    }
+                    let oy = oam[i * 4] as i16 - 16;
+                    let attr = TileAttr::from(oam[i * 4 + 3]);
+                    let mut tile = oam[i * 4 + 2];
+                    if height == 16 {
+                        tile &= 0xFE;
+                    }
+                    let mut row = (ly as i16 - oy) as u8;
+                    if attr.yflip {
+                        row = height as u8 - 1 - row;
+                    }
+                    let mut col = (x as i16 - ox) as u8;
+                    if attr.xflip {
+                        col = 7 - col;
+                    }
+                    let bank = if self.cgb { attr.bank } else { 0 };
+                    let addr = tile as u16 * 16 + row as u16 * 2;
+                    let index = Self::tile_pixel(vram, bank, addr, col);
+                    if index == 0 {
+                        continue;
+                    }
+                    let bg_wins = master_priority
+                        && bg_index[x] != 0
+                        && (attr.priority || bg_priority[x]);
+                    if !bg_wins {
+                        *pixel = self.obj_colour(attr, index);
+                    }
+                    break;
+                }
+            }
+        }
+
+        let start = ly as usize * SCREEN_WIDTH;
+        self.framebuffer[start..start + SCREEN_WIDTH].copy_from_slice(&line);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bg_attr_selects_bank_and_flip() {
+        let mut vram = Vram::default();
+        let mut ppu = Ppu::default();
+        ppu.set_cgb(true);
+        /* Tile 0 in bank 1 has its leftmost pixel set to colour 3 */
+        vram.set_bank(1);
+        vram.write(0x0000, 0x80);
+        vram.write(0x0001, 0x80);
+        /* First map entry uses bank 1 and is flipped horizontally */
+        vram.write(0x1800, 0b0010_1000);
+        let (index, attr) = ppu.bg_pixel(&vram, 0x1800, 7, 0);
+        assert!(attr.bank == 1 && attr.xflip);
+        assert!(index == 3);
+        let (index, _) = ppu.bg_pixel(&vram, 0x1800, 0, 0);
+        assert!(index == 0);
+    }
+
+    #[test]
+    fn dmg_ignores_bank1_attributes() {
+        let mut vram = Vram::default();
+        let ppu = Ppu::default();
+        vram.write(0x0000, 0x80);
+        vram.set_bank(1);
+        vram.write(0x1800, 0b0010_1000);
+        let (index, attr) = ppu.bg_pixel(&vram, 0x1800, 0, 0);
+        assert!(attr == TileAttr::default());
+        assert!(index == 1);
+    }
+
+    #[test]
+    fn palette_auto_increment() {
+        let mut ppu = Ppu::default();
+        ppu.set_cgb(true);
+        ppu.write(0xFF68, 0x80 | 0x3E);
+        ppu.write(0xFF69, 0x1F);
+        ppu.write(0xFF69, 0x00);
+        ppu.write(0xFF69, 0xE0);
+        // Index wraps around after the last byte
+        assert!(ppu.read(0xFF68) == 0xC1);
+        assert!(ppu.bg_palettes.colour(7, 3) == 0x001F);
+        assert!(ppu.bg_palettes.colour(0, 0) & 0xFF == 0xE0);
+        ppu.write(0xFF68, 0x00);
+        assert!(ppu.read(0xFF69) == 0xE0);
+        ppu.write(0xFF69, 0x12);
+        assert!(ppu.read(0xFF68) == 0x40);
+    }
+
+    #[test]
+    fn palettes_locked_in_mode3() {
+        let mut ppu = Ppu::default();
+        ppu.set_cgb(true);
+        ppu.mode = Mode::Drawing;
+        ppu.write(0xFF6A, 0x80);
+        ppu.write(0xFF6B, 0x55);
+        assert!(ppu.read(0xFF6B) == 0xFF);
+        assert!(ppu.read(0xFF6A) == 0xC1);
+        ppu.mode = Mode::HBlank;
+        ppu.write(0xFF6A, 0x00);
+        assert!(ppu.read(0xFF6B) == 0xFF);
+    }
+
+    #[test]
+    fn compat_mode_colours_shades() {
+        let mut ppu = Ppu::default();
+        ppu.set_compat(true);
+        ppu.bgp = 0b11_10_01_00;
+        ppu.bg_palettes.data[2] = 0x1F;
+        ppu.bg_palettes.data[3] = 0x00;
+        assert!(ppu.bg_colour(5, 1) == 0x001F);
+        ppu.set_compat(false);
+        assert!(ppu.bg_colour(5, 1) == DMG_COLOURS[1]);
+        // Palette registers are not visible outside of CGB mode
+        assert!(ppu.read(0xFF68) == 0xFF);
+    }
+
+    #[test]
+    fn compat_mode_boot_palettes() {
+        let mut vram = Vram::default();
+        let mut ppu = Ppu::default();
+        ppu.set_compat(true);
+        ppu.lcdc = 0x91;
+        ppu.bgp = 0b11_10_01_00;
+        /* Leftmost pixel of tile 0 has colour 1, the rest colour 0 */
+        vram.write(0x0000, 0x80);
+        ppu.render_line(&vram, &[0; 0xA0]);
+        assert!(ppu.framebuffer[0] == COMPAT_BG_COLOURS[1]);
+        assert!(ppu.framebuffer[1] == COMPAT_BG_COLOURS[0]);
+        assert!(ppu.framebuffer[0] != DMG_COLOURS[1]);
+    }
+}
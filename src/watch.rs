@@ -0,0 +1,78 @@
+use std::fmt::{self, Display, Formatter};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+/// Pauses emulation when an address range is accessed on the memory bus,
+/// optionally only when a given value is read or written
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Watchpoint {
+    pub start: u16,
+    pub end: u16, /* inclusive */
+    pub access: Access,
+    pub value: Option<u8>,
+}
+
+impl Watchpoint {
+    pub fn matches(&self, addr: u16, val: u8, write: bool) -> bool {
+        let access = match self.access {
+            Access::Read => !write,
+            Access::Write => write,
+            Access::ReadWrite => true,
+        };
+        access
+            && (self.start..=self.end).contains(&addr)
+            && self.value.map_or(true, |value| value == val)
+    }
+}
+
+impl Display for Watchpoint {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let access = match self.access {
+            Access::Read => "read",
+            Access::Write => "write",
+            Access::ReadWrite => "access",
+        };
+        write!(f, "{} {:04X}", access, self.start)?;
+        if self.end != self.start {
+            write!(f, "-{:04X}", self.end)?;
+        }
+        if let Some(value) = self.value {
+            write!(f, " == {:02X}", value)?;
+        }
+        Ok(())
+    }
+}
+
+/// First watchpoint triggered since the last `Memory::take_watch_hit`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WatchHit {
+    pub index: usize,
+    pub addr: u16,
+    pub value: u8,
+    pub write: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matching() {
+        let watch = Watchpoint {
+            start: 0xC000,
+            end: 0xC0FF,
+            access: Access::Write,
+            value: Some(0x42),
+        };
+        assert!(watch.matches(0xC0A0, 0x42, true));
+        assert!(!watch.matches(0xC0A0, 0x42, false));
+        assert!(!watch.matches(0xC0A0, 0x41, true));
+        assert!(!watch.matches(0xC100, 0x42, true));
+        assert!(watch.to_string() == "write C000-C0FF == 42");
+    }
+}
@@ -0,0 +1,253 @@
+//! Golden image regression tests, every case of `screenshots/manifest.txt`
+//! is rendered with `uboy run` and compared pixel by pixel.
+
+use std::{
+    env,
+    fs::{self, File},
+    io::BufWriter,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+const WIDTH: u32 = 160;
+const HEIGHT: u32 = 144;
+/* Exit status of `uboy run` when the cpu locked up */
+const LOCKUP_EXIT_STATUS: i32 = 3;
+
+enum Expected {
+    Png(PathBuf),
+    Hash(u64),
+}
+
+struct Case {
+    name: String,
+    rom: PathBuf,
+    frames: u64,
+    input: Option<PathBuf>,
+    expected: Expected,
+}
+
+fn parse_manifest(path: &Path) -> Vec<Case> {
+    let dir = path.parent().unwrap();
+    let text = fs::read_to_string(path).expect("Can not read the manifest");
+    let mut cases = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        assert!(
+            fields.len() >= 3,
+            "Manifest line {} needs a rom, a frame count and the expected image",
+            number + 1
+        );
+        let expected = match fields[2].strip_prefix("hash:") {
+            Some(hash) => Expected::Hash(
+                u64::from_str_radix(hash, 16)
+                    .unwrap_or_else(|_| panic!("Manifest line {}: invalid hash", number + 1)),
+            ),
+            None => Expected::Png(dir.join(fields[2])),
+        };
+        let input = fields[3..]
+            .iter()
+            .find_map(|field| field.strip_prefix("input="))
+            .map(|script| dir.join(script));
+        cases.push(Case {
+            name: Path::new(fields[0])
+                .file_stem()
+                .unwrap()
+                .to_string_lossy()
+                .into_owned(),
+            rom: dir.join(fields[0]),
+            frames: fields[1]
+                .parse()
+                .unwrap_or_else(|_| panic!("Manifest line {}: invalid frame count", number + 1)),
+            input,
+            expected,
+        });
+    }
+    cases
+}
+
+/// Decodes a PNG into RGB pixels
+fn read_png(path: &Path) -> Result<(u32, u32, Vec<u8>), String> {
+    let file = File::open(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let mut decoder = png::Decoder::new(file);
+    decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+    let mut reader = decoder
+        .read_info()
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+    let mut buf = vec![0; reader.output_buffer_size()];
+    let info = reader
+        .next_frame(&mut buf)
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+    buf.truncate(info.buffer_size());
+    let rgb = match info.color_type {
+        png::ColorType::Rgb => buf,
+        png::ColorType::Rgba => buf.chunks(4).flat_map(|p| p[..3].to_vec()).collect(),
+        png::ColorType::Grayscale => buf.iter().flat_map(|g| vec![*g; 3]).collect(),
+        png::ColorType::GrayscaleAlpha => buf.chunks(2).flat_map(|p| vec![p[0]; 3]).collect(),
+        png::ColorType::Indexed => return Err(format!("{}: palette not expanded", path.display())),
+    };
+    Ok((info.width, info.height, rgb))
+}
+
+fn write_png(path: &Path, rgb: &[u8]) {
+    let file = BufWriter::new(File::create(path).unwrap());
+    let mut encoder = png::Encoder::new(file, WIDTH, HEIGHT);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header().unwrap();
+    writer.write_image_data(rgb).unwrap();
+}
+
+/// FNV-1a of the RGB pixels, what `hash:` entries are compared against
+fn hash(rgb: &[u8]) -> u64 {
+    rgb.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// Number of differing pixels and an image where they are red on a dimmed
+/// copy of the expected image
+fn diff(expected: &[u8], actual: &[u8]) -> (usize, Vec<u8>) {
+    let mut count = 0;
+    let mut image = Vec::with_capacity(expected.len());
+    for (e, a) in expected.chunks(3).zip(actual.chunks(3)) {
+        if e == a {
+            let grey = ((e[0] as u16 + e[1] as u16 + e[2] as u16) / 12) as u8;
+            image.extend_from_slice(&[grey, grey, grey]);
+        } else {
+            count += 1;
+            image.extend_from_slice(&[0xFF, 0x00, 0x00]);
+        }
+    }
+    (count, image)
+}
+
+/// Runs the case through the emulator binary, returns the RGB screenshot
+fn render(case: &Case, out_dir: &Path) -> Result<Vec<u8>, String> {
+    let screenshot = out_dir.join(format!("{}.actual.png", case.name));
+    let mut command = Command::new(env!("CARGO_BIN_EXE_uboy"));
+    command
+        .arg("run")
+        .args(["--log-level", "off"])
+        .args(["--frames", &case.frames.to_string()])
+        .arg("--screenshot")
+        .arg(&screenshot);
+    if let Some(input) = &case.input {
+        command.arg("--input").arg(input);
+    }
+    let output = command
+        .arg(&case.rom)
+        .output()
+        .map_err(|e| format!("Can not start uboy: {}", e))?;
+    // A hung cpu makes the screenshot meaningless, even if it matches
+    if output.status.code() == Some(LOCKUP_EXIT_STATUS) {
+        return Err(format!(
+            "cpu locked up: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    if !output.status.success() {
+        return Err(format!(
+            "uboy failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    let (_, _, rgb) = read_png(&screenshot)?;
+    Ok(rgb)
+}
+
+fn check(case: &Case, out_dir: &Path) -> Result<(), String> {
+    let actual = render(case, out_dir)?;
+    match &case.expected {
+        Expected::Hash(expected) => {
+            let actual = hash(&actual);
+            if actual != *expected {
+                return Err(format!(
+                    "hash is {:016x}, expected {:016x}",
+                    actual, expected
+                ));
+            }
+        }
+        Expected::Png(path) => {
+            if env::var_os("UBOY_BLESS").is_some() {
+                write_png(path, &actual);
+                println!("{}: wrote {}", case.name, path.display());
+                return Ok(());
+            }
+            let (width, height, expected) =
+                read_png(path).map_err(|e| format!("{}, run with UBOY_BLESS=1 to create it", e))?;
+            if (width, height) != (WIDTH, HEIGHT) {
+                return Err(format!("expected image is {}x{}", width, height));
+            }
+            let (count, image) = diff(&expected, &actual);
+            if count != 0 {
+                let diff_path = out_dir.join(format!("{}.diff.png", case.name));
+                write_png(&diff_path, &image);
+                return Err(format!(
+                    "{} pixels differ, see {}",
+                    count,
+                    diff_path.display()
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn golden_images() {
+    let manifest = env::var_os("UBOY_SCREENSHOT_MANIFEST")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| {
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/screenshots/manifest.txt")
+        });
+    let out_dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("screenshots");
+    fs::create_dir_all(&out_dir).unwrap();
+
+    let mut failures = Vec::new();
+    let mut checked = 0;
+    for case in parse_manifest(&manifest) {
+        if !case.rom.exists() {
+            println!("{}: skipped, {} not found", case.name, case.rom.display());
+            continue;
+        }
+        checked += 1;
+        match check(&case, &out_dir) {
+            Ok(()) => println!("{}: ok", case.name),
+            Err(e) => failures.push(format!("{}: {}", case.name, e)),
+        }
+    }
+    assert!(checked > 0, "No rom of {} was found", manifest.display());
+    assert!(failures.is_empty(), "\n{}", failures.join("\n"));
+}
+
+#[test]
+fn diff_marks_changed_pixels() {
+    let expected = vec![0xFF; 9];
+    let mut actual = expected.clone();
+    actual[4] = 0;
+    let (count, image) = diff(&expected, &actual);
+    assert!(count == 1);
+    assert!(image == vec![0x3F, 0x3F, 0x3F, 0xFF, 0x00, 0x00, 0x3F, 0x3F, 0x3F]);
+}
+
+#[test]
+fn manifest_entries() {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR"));
+    let manifest = dir.join("manifest_entries.txt");
+    fs::write(
+        &manifest,
+        "# comment\nroms/a.gb 60 a.png\n\nb.gbc 10 hash:00ff input=b.input # title\n",
+    )
+    .unwrap();
+    let cases = parse_manifest(&manifest);
+    assert!(cases.len() == 2);
+    assert!(cases[0].name == "a" && cases[0].frames == 60 && cases[0].input.is_none());
+    assert!(matches!(&cases[0].expected, Expected::Png(path) if *path == dir.join("a.png")));
+    assert!(matches!(cases[1].expected, Expected::Hash(0xFF)));
+    assert!(cases[1].input.as_deref() == Some(dir.join("b.input").as_path()));
+}